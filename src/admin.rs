@@ -0,0 +1,280 @@
+use crate::{
+	limits, metrics,
+	register::Registration,
+	secret::Secret,
+	sockets::{close_codes, ControlMessage, SocketType},
+	Registrations, CONFIG,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+	net::SocketAddr,
+	sync::{atomic::Ordering, Arc},
+};
+use thiserror::Error;
+use tracing::{info, warn};
+use warp::{http::StatusCode, ws::Message, Filter, Rejection, Reply};
+
+#[derive(Debug, Error)]
+pub enum Rejections {
+	#[error("The admin token is missing or incorrect")]
+	InvalidToken,
+	// the other admin routes share paths with this one, so a plain `not_found` would lose out to
+	// their `method_not_allowed`
+	#[error("The registration or connection does not exist")]
+	NotFound,
+}
+
+impl Rejections {
+	pub fn status(&self) -> StatusCode {
+		match self {
+			Rejections::InvalidToken => StatusCode::UNAUTHORIZED,
+			Rejections::NotFound => StatusCode::NOT_FOUND,
+		}
+	}
+
+	/// The code that clients get in the body of the response. These should never change, since
+	/// clients match on them.
+	pub fn code(&self) -> &'static str {
+		match self {
+			Rejections::InvalidToken => "invalid_admin_token",
+			Rejections::NotFound => "not_found",
+		}
+	}
+}
+
+impl warp::reject::Reject for Rejections {}
+
+#[derive(Deserialize)]
+pub struct NoticeRequest {
+	pub message: String,
+}
+
+#[derive(Serialize)]
+struct ConnectionInfo<'a> {
+	id: &'a str,
+	sock_type: SocketType,
+	remote_addr: Option<SocketAddr>,
+	connected_at: i64,
+	bytes_sent: u64,
+	bytes_received: u64,
+	rate_limited: u64,
+	rtt_ms: Option<f64>,
+	host_key: bool,
+}
+
+/// Only lets requests through if they have an `Authorization: Bearer <admin_token>` header.
+/// If the server has no `admin_token`, the admin API doesn't exist at all, so every request is
+/// rejected as not found.
+pub fn authorized() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+	warp::header::optional("authorization")
+		.and(limits::client_addr())
+		.and_then(check_token)
+		.untuple_one()
+}
+
+/// Whether `auth`, the value of an `Authorization` header, holds the admin token
+pub fn token_matches(token: &Secret, auth: Option<&str>) -> bool {
+	let provided = auth
+		.and_then(|a| a.strip_prefix("Bearer "))
+		.map(|p| blake2b_simd::blake2b(p.as_bytes()));
+
+	// `blake2b_simd::Hash` compares in constant time, so this doesn't leak how much of the
+	// token was right
+	provided == Some(blake2b_simd::blake2b(token.expose().as_bytes()))
+}
+
+async fn check_token(auth: Option<String>, addr: Option<SocketAddr>) -> Result<(), Rejection> {
+	let token = match CONFIG.read().await.admin_token {
+		Some(ref token) => token.clone(),
+		None => return Err(metrics::not_found()),
+	};
+
+	limits::check_ip(addr).await?;
+
+	if token_matches(&token, auth.as_deref()) {
+		Ok(())
+	} else {
+		warn!(ip = ?addr.map(|a| a.ip()), "Rejecting admin request with an invalid token");
+		Err(metrics::reject(Rejections::InvalidToken))
+	}
+}
+
+fn not_found() -> Rejection {
+	metrics::reject(Rejections::NotFound)
+}
+
+async fn registration_info(reg: &Registration) -> serde_json::Value {
+	let conns = reg.connections.read().await;
+
+	let connections: Vec<ConnectionInfo> = conns
+		.iter()
+		.map(|con| ConnectionInfo {
+			id: &con.uuid,
+			sock_type: con.sock_type,
+			remote_addr: con.remote_addr,
+			connected_at: con.connected_at,
+			bytes_sent: con.queue.sent_bytes.load(Ordering::Relaxed),
+			bytes_received: con.bytes_received.load(Ordering::Relaxed),
+			rate_limited: con.rate_limited.load(Ordering::Relaxed),
+			rtt_ms: con.rtt_ms(),
+			host_key: con.privileged,
+		})
+		.collect();
+
+	serde_json::json!({
+		"id": reg.uuid,
+		"reg_type": reg.reg_type,
+		"expires_at": reg.expires_at,
+		"public": reg.public,
+		"locked": *reg.locked.read().await,
+		"detached": reg.detached.read().await.len(),
+		"connections": connections
+	})
+}
+
+/// Handles `GET /admin/registrations`, which lists every registration along with every
+/// connection to it
+pub async fn list_registrations(rgs: Registrations) -> Result<impl Reply, Rejection> {
+	info!("Listing registrations for an admin...");
+
+	let regs = rgs.read().await;
+
+	let mut ids: Vec<&String> = regs.keys().collect();
+	ids.sort();
+
+	let mut list = Vec::with_capacity(ids.len());
+
+	for id in ids {
+		list.push(registration_info(&regs[id]).await);
+	}
+
+	Ok(warp::reply::json(&serde_json::json!({
+		"registrations": list
+	})))
+}
+
+/// Handles `GET /admin/registrations/{id}`
+pub async fn get_registration(id: String, rgs: Registrations) -> Result<impl Reply, Rejection> {
+	let regs = rgs.read().await;
+	let reg = regs.get(&id).ok_or_else(not_found)?;
+
+	Ok(warp::reply::json(&registration_info(reg).await))
+}
+
+/// Handles `DELETE /admin/registrations/{id}`, which removes a registration without needing
+/// its keys
+#[tracing::instrument(name = "admin", skip_all, fields(reg_id = %id))]
+pub async fn remove_registration(
+	id: String,
+	addr: Option<SocketAddr>,
+	rgs: Registrations,
+) -> Result<impl Reply, Rejection> {
+	// the lock is held until it's out of the store too, so that a new registration with the
+	// same id can't be persisted before this one is removed
+	let mut regs = rgs.write().await;
+	let reg = regs.remove(&id).ok_or_else(not_found)?;
+
+	reg.close(
+		close_codes::REMOVED,
+		"Registration removed by an administrator",
+		"admin_removed",
+	)
+	.await;
+	drop(regs);
+
+	info!(target: "audit", ip = ?addr.map(|a| a.ip()), "Admin removed registration");
+
+	Ok(warp::reply::json(&serde_json::json!({ "id": id })))
+}
+
+/// Handles `DELETE /admin/registrations/{id}/connections/{conn_id}`, which disconnects a single
+/// connection without letting it resume its session
+#[tracing::instrument(name = "admin", skip_all, fields(reg_id = %id, conn_id = %conn_id))]
+pub async fn kick_connection(
+	id: String,
+	conn_id: String,
+	addr: Option<SocketAddr>,
+	rgs: Registrations,
+) -> Result<impl Reply, Rejection> {
+	let regs = rgs.read().await;
+	let reg = regs.get(&id).ok_or_else(not_found)?;
+
+	let conns = reg.connections.read().await;
+	let con = conns
+		.iter()
+		.find(|c| c.uuid == conn_id)
+		.ok_or_else(not_found)?;
+
+	con.queue.close(Some(Message::close_with(
+		close_codes::KICKED,
+		"Kicked by an administrator",
+	)));
+
+	info!(target: "audit", ip = ?addr.map(|a| a.ip()), "Admin kicked connection");
+
+	Ok(warp::reply::json(&serde_json::json!({
+		"id": id,
+		"connection": conn_id
+	})))
+}
+
+/// Handles `POST /admin/registrations/{id}/notice`, which sends a notice from the server to
+/// every connection in a registration
+#[tracing::instrument(name = "admin", skip_all, fields(reg_id = %id))]
+pub async fn send_notice(
+	id: String,
+	body: NoticeRequest,
+	addr: Option<SocketAddr>,
+	rgs: Registrations,
+) -> Result<impl Reply, Rejection> {
+	let regs = rgs.read().await;
+	let reg = regs.get(&id).ok_or_else(not_found)?;
+
+	let notice = Arc::new(
+		ControlMessage::Notice {
+			message: body.message,
+		}
+		.to_message(),
+	);
+
+	let mut sent = 0;
+
+	for con in reg.connections.read().await.iter() {
+		if con.send_shared(notice.clone()) {
+			sent += 1;
+		} else {
+			warn!(conn_id = %con.uuid, "Failed to queue notice");
+		}
+	}
+
+	info!(target: "audit", ip = ?addr.map(|a| a.ip()), sent, "Admin sent notice");
+
+	Ok(warp::reply::json(&serde_json::json!({
+		"id": id,
+		"sent": sent
+	})))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn token_has_to_be_an_exact_bearer_token() {
+		let token = Secret::new("admin-token".to_owned());
+
+		assert!(token_matches(&token, Some("Bearer admin-token")));
+
+		for auth in [
+			None,
+			Some(""),
+			Some("admin-token"),
+			Some("Bearer admin-toke"),
+			Some("Bearer admin-token "),
+			Some("bearer admin-token"),
+			Some("Basic admin-token"),
+		] {
+			assert!(!token_matches(&token, auth), "{:?}", auth);
+		}
+	}
+}
@@ -1,142 +1,760 @@
+use crate::{
+	hashing::HashVariant,
+	limits::TrustedProxy,
+	listeners::Listener,
+	logging::LogFormat,
+	queue::QueuePolicy,
+	register::{RateLimitPolicy, MAX_KEEPALIVE_SECS},
+	secret::Secret,
+	stats::StatsAccess,
+	store::StoreType,
+};
+use std::{env, fs, str::FromStr};
+use thiserror::Error;
+use tracing::level_filters::LevelFilter;
 use uuid::Uuid;
 
-#[macro_export]
-macro_rules! log_vbs{
-	($vbs:expr, $out:expr, $msg:expr$(, $args:expr)*) => {
-		if $vbs {
-			crate::log!($out, Color::Purple, $msg$(, $args)*)
-		}
-	}
-}
+/// Every environment variable that configures the server starts with this
+pub const ENV_PREFIX: &str = "WS_ROUTER_";
 
-#[macro_export]
-macro_rules! log{
-	($out:expr, $col:expr, $msg:expr$(, $args:expr)*) => {
-		if $out {
-			Config::log(format!($msg$(, $args)*), $col);
-		}
-	}
-}
+/// The secret key is the salt that keys are hashed with, and argon2 won't use a salt that's any
+/// shorter than this
+pub const MIN_SECRET_KEY_LEN: usize = 8;
+/// The most KiB that argon2 may use to hash a single key. This is per key being hashed, so the
+/// most that hashing can use at once is this times `hash_concurrency`.
+pub const MAX_ARGON2_MEMORY: u32 = 1024 * 1024;
 
-#[macro_export]
-macro_rules! err{
-	($out:expr, $msg:expr$(, $args:expr)*) => {
-		if $out {
-			Config::err(format!($msg$(, $args)*))
-		}
-	}
-}
+/// The name of every option, which is the same in the config file, in environment variables
+/// (uppercased, after `ENV_PREFIX`), and on the command line
+pub const FIELDS: [&str; 55] = [
+	"port",
+	"listen",
+	"quiet",
+	"verbose",
+	"log_level",
+	"log_format",
+	"reject",
+	"secret_key",
+	"secure",
+	"auto_remove",
+	"key_file",
+	"cert_file",
+	"store",
+	"store_path",
+	"default_ttl",
+	"max_ttl",
+	"idle_ttl",
+	"unused_ttl",
+	"reap_interval",
+	"resume_grace",
+	"resume_buffer_len",
+	"resume_buffer_bytes",
+	"queue_len",
+	"queue_policy",
+	"argon2_memory",
+	"argon2_iterations",
+	"argon2_variant",
+	"hash_concurrency",
+	"verify_cache_ttl",
+	"ip_rate_limit",
+	"ip_rate_burst",
+	"id_rate_limit",
+	"id_rate_burst",
+	"max_failed_attempts",
+	"lockout_duration",
+	"trusted_proxies",
+	"max_metadata_bytes",
+	"drain_timeout",
+	"shutdown_reason",
+	"shutdown_snapshot",
+	"ping_interval",
+	"pong_timeout",
+	"max_missed_pongs",
+	"admin_token",
+	"stats",
+	"stats_interval",
+	"max_message_size",
+	"max_frame_size",
+	"message_rate_limit",
+	"message_rate_burst",
+	"byte_rate_limit",
+	"byte_rate_burst",
+	"total_message_rate_limit",
+	"total_byte_rate_limit",
+	"rate_limit_policy",
+];
 
 pub struct Config {
 	pub port: u16,
+	pub listen: Vec<Listener>,
 	pub quiet: bool,
 	pub verbose: bool,
+	pub log_level: LevelFilter,
+	pub log_format: LogFormat,
 	pub reject_no_id: bool,
-	pub secret_key: String,
+	pub secret_key: Secret,
 	pub secure: bool,
 	pub auto_remove: bool,
 	pub key_file: Option<String>,
 	pub cert_file: Option<String>,
+	pub store: StoreType,
+	pub store_path: String,
+	pub default_ttl: Option<u64>,
+	pub max_ttl: Option<u64>,
+	pub idle_ttl: Option<u64>,
+	pub unused_ttl: u64,
+	pub reap_interval: u64,
+	pub resume_grace: u64,
+	pub resume_buffer_len: usize,
+	pub resume_buffer_bytes: usize,
+	pub queue_len: usize,
+	pub queue_policy: QueuePolicy,
+	pub argon2_memory: u32,
+	pub argon2_iterations: u32,
+	pub argon2_variant: HashVariant,
+	pub hash_concurrency: usize,
+	pub verify_cache_ttl: u64,
+	pub ip_rate_limit: f64,
+	pub ip_rate_burst: u32,
+	pub id_rate_limit: f64,
+	pub id_rate_burst: u32,
+	pub max_failed_attempts: u32,
+	pub lockout_duration: u64,
+	pub trusted_proxies: Vec<TrustedProxy>,
+	pub max_metadata_bytes: usize,
+	pub drain_timeout: u64,
+	pub shutdown_reason: Option<String>,
+	pub shutdown_snapshot: Option<String>,
+	pub ping_interval: u64,
+	pub pong_timeout: u64,
+	pub max_missed_pongs: u32,
+	pub admin_token: Option<Secret>,
+	pub stats: StatsAccess,
+	pub stats_interval: u64,
+	pub max_message_size: usize,
+	pub max_frame_size: usize,
+	pub message_rate_limit: f64,
+	pub message_rate_burst: u64,
+	pub byte_rate_limit: f64,
+	pub byte_rate_burst: u64,
+	pub total_message_rate_limit: f64,
+	pub total_byte_rate_limit: f64,
+	pub rate_limit_policy: RateLimitPolicy,
 }
 
 impl Config {
 	pub fn default() -> Config {
 		Config {
 			port: 8741,
+			listen: Vec::new(),
 			quiet: false,
 			verbose: false,
+			log_level: LevelFilter::INFO,
+			log_format: LogFormat::Text,
 			reject_no_id: false,
-			secret_key: Uuid::new_v4().to_string(),
+			secret_key: Secret::new(Uuid::new_v4().to_string()),
 			secure: false,
 			auto_remove: false,
 			key_file: None,
 			cert_file: None,
+			store: StoreType::Memory,
+			store_path: "registrations.json".to_owned(),
+			default_ttl: None,
+			max_ttl: None,
+			idle_ttl: None,
+			unused_ttl: 24 * 60 * 60,
+			reap_interval: 60,
+			resume_grace: 30,
+			resume_buffer_len: 100,
+			resume_buffer_bytes: 1024 * 1024,
+			queue_len: 256,
+			queue_policy: QueuePolicy::DropOldest,
+			// these match argon2::Config::default(), which is what keys were always hashed with
+			argon2_memory: 4096,
+			argon2_iterations: 3,
+			argon2_variant: HashVariant::Argon2i,
+			hash_concurrency: std::thread::available_parallelism()
+				.map(|n| n.get())
+				.unwrap_or(4),
+			verify_cache_ttl: 300,
+			ip_rate_limit: 0.0,
+			ip_rate_burst: 20,
+			id_rate_limit: 0.0,
+			id_rate_burst: 10,
+			max_failed_attempts: 5,
+			lockout_duration: 300,
+			trusted_proxies: Vec::new(),
+			max_metadata_bytes: 4096,
+			drain_timeout: 10,
+			shutdown_reason: None,
+			shutdown_snapshot: None,
+			ping_interval: 30,
+			pong_timeout: 10,
+			max_missed_pongs: 3,
+			admin_token: None,
+			stats: StatsAccess::Aggregate,
+			stats_interval: 10,
+			max_message_size: 1024 * 1024,
+			max_frame_size: 1024 * 1024,
+			message_rate_limit: 0.0,
+			message_rate_burst: 20,
+			byte_rate_limit: 0.0,
+			byte_rate_burst: 1024 * 1024,
+			total_message_rate_limit: 0.0,
+			total_byte_rate_limit: 0.0,
+			rate_limit_policy: RateLimitPolicy::Drop,
 		}
 	}
 
-	pub fn parse_args(&mut self, matches: clap::ArgMatches) -> bool {
-		if matches.is_present("quiet") {
-			self.quiet = true;
-		}
+	/// Builds the config from every source, in order of increasing precedence: the defaults,
+	/// then the config file given by `--config` (or `WS_ROUTER_CONFIG`), then `WS_ROUTER_*`
+	/// environment variables, then command-line flags
+	pub fn load(matches: &clap::ArgMatches) -> Result<Config, ConfigError> {
+		let mut conf = Config::default();
 
-		if matches.is_present("reject") {
-			self.reject_no_id = true;
+		let path = matches
+			.value_of("config")
+			.map(|p| p.to_owned())
+			.or_else(|| env::var(format!("{}CONFIG", ENV_PREFIX)).ok());
+
+		if let Some(path) = path {
+			conf.apply_file(&path)?;
 		}
 
-		if let Some(port) = matches.value_of("port") {
-			if let Ok(port_int) = port.parse() {
-				self.port = port_int;
-			} else {
-				err!(
-					!self.quiet,
-					"Please only use values from 0 = 2^16 for the port (you input '{}')",
-					port
-				);
-				return false;
+		conf.apply_env()?;
+
+		for name in FIELDS {
+			let origin = format!("--{}", name);
+
+			if let Some(value) = matches.value_of(name) {
+				conf.apply(name, value, &origin)?;
+			} else if matches.is_present(name) {
+				// flags that don't take a value just turn their option on
+				conf.apply(name, "true", &origin)?;
 			}
 		}
 
-		if let Some(key) = matches.value_of("secret_key") {
-			self.secret_key = key.to_owned();
+		conf.validate()?;
+		Ok(conf)
+	}
+
+	fn apply_file(&mut self, path: &str) -> Result<(), ConfigError> {
+		let contents =
+			fs::read_to_string(path).map_err(|err| ConfigError::ReadFile(path.to_owned(), err))?;
+
+		let table: toml::value::Table = toml::from_str(&contents)
+			.map_err(|err| ConfigError::ParseFile(path.to_owned(), err))?;
+
+		let origin = format!("config file '{}'", path);
+
+		for (name, value) in table {
+			let value = match value {
+				toml::Value::String(s) => s,
+				toml::Value::Integer(i) => i.to_string(),
+				toml::Value::Float(f) => f.to_string(),
+				toml::Value::Boolean(b) => b.to_string(),
+				toml::Value::Array(arr) => arr
+					.into_iter()
+					.map(|v| match v {
+						toml::Value::String(s) => s,
+						other => other.to_string(),
+					})
+					.collect::<Vec<_>>()
+					.join(","),
+				other => other.to_string(),
+			};
+
+			self.apply(&name, &value, &origin)?;
 		}
 
-		if matches.is_present("secure") {
-			self.key_file = matches.value_of("key_file").map(|k| k.to_owned());
+		Ok(())
+	}
+
+	fn apply_env(&mut self) -> Result<(), ConfigError> {
+		// `env::vars` panics if any variable isn't unicode, even ones that have nothing to do
+		// with us, so only ours are converted
+		for (key, value) in env::vars_os() {
+			let key = match key.to_str() {
+				Some(key) if key.starts_with(ENV_PREFIX) => key.to_owned(),
+				_ => continue,
+			};
 
-			self.cert_file = matches.value_of("cert_file").map(|c| c.to_owned());
+			let name = key[ENV_PREFIX.len()..].to_lowercase();
 
-			if self.cert_file.is_none() || self.key_file.is_none() {
-				err!(!self.quiet, "Please enter both a key_file and a cert_file");
-				return false;
+			// this one just says where the config file is, which we've already dealt with
+			if name == "config" {
+				continue;
 			}
 
-			self.secure = true;
+			let value = value
+				.into_string()
+				.map_err(|_| ConfigError::NotUnicode(key.to_owned()))?;
+
+			self.apply(&name, &value, &key)?;
 		}
 
-		if matches.is_present("verbose") {
-			self.verbose = true;
+		Ok(())
+	}
+
+	/// Sets the option called `name` from its string form. `origin` is only used to say where
+	/// the value came from if it's invalid.
+	fn apply(&mut self, name: &str, value: &str, origin: &str) -> Result<(), ConfigError> {
+		let raw = RawValue {
+			name,
+			value,
+			origin,
+		};
+
+		match name {
+			"port" => self.port = raw.parse("a port number from 0 to 65535")?,
+			"listen" => {
+				self.listen = raw.parse_list(
+					"addresses like '0.0.0.0:8741', 'tls://[::]:443', or 'unix:<path>'",
+				)?
+			}
+			// these two conflict, so turning one of them on turns the other off. Sources are
+			// applied in order of precedence, so the one that's set last wins.
+			"quiet" => {
+				self.quiet = raw.parse("true or false")?;
+				self.verbose &= !self.quiet;
+			}
+			"verbose" => {
+				self.verbose = raw.parse("true or false")?;
+				self.quiet &= !self.verbose;
+			}
+			"log_level" => {
+				self.log_level = raw.parse("'off', 'error', 'warn', 'info', 'debug', or 'trace'")?
+			}
+			"log_format" => self.log_format = raw.parse("'text' or 'json'")?,
+			"reject" => self.reject_no_id = raw.parse("true or false")?,
+			"secret_key" => self.secret_key = Secret::new(value.to_owned()),
+			"secure" => self.secure = raw.parse("true or false")?,
+			"auto_remove" => self.auto_remove = raw.parse("true or false")?,
+			"key_file" => self.key_file = Some(value.to_owned()),
+			"cert_file" => self.cert_file = Some(value.to_owned()),
+			"store" => self.store = raw.parse("'memory' or 'file'")?,
+			"store_path" => self.store_path = value.to_owned(),
+			"default_ttl" => self.default_ttl = Some(raw.parse("a number of seconds")?),
+			"max_ttl" => self.max_ttl = Some(raw.parse("a number of seconds")?),
+			"idle_ttl" => self.idle_ttl = Some(raw.parse("a number of seconds")?),
+			"unused_ttl" => self.unused_ttl = raw.parse("a number of seconds")?,
+			"reap_interval" => self.reap_interval = raw.parse("a number of seconds")?,
+			"resume_grace" => self.resume_grace = raw.parse("a number of seconds")?,
+			"resume_buffer_len" => self.resume_buffer_len = raw.parse("a number of messages")?,
+			"resume_buffer_bytes" => self.resume_buffer_bytes = raw.parse("a number of bytes")?,
+			"queue_len" => self.queue_len = raw.parse("a number of messages")?,
+			"queue_policy" => {
+				self.queue_policy = raw.parse("'drop_oldest', 'drop_newest', or 'disconnect'")?
+			}
+			"argon2_memory" => self.argon2_memory = raw.parse("a number of KiB")?,
+			"argon2_iterations" => self.argon2_iterations = raw.parse("a number of iterations")?,
+			"argon2_variant" => {
+				self.argon2_variant = raw.parse("'argon2d', 'argon2i', or 'argon2id'")?
+			}
+			"hash_concurrency" => self.hash_concurrency = raw.parse("a number of keys")?,
+			"verify_cache_ttl" => self.verify_cache_ttl = raw.parse("a number of seconds")?,
+			"ip_rate_limit" => self.ip_rate_limit = raw.parse("a number of requests per second")?,
+			"ip_rate_burst" => self.ip_rate_burst = raw.parse("a number of requests")?,
+			"id_rate_limit" => self.id_rate_limit = raw.parse("a number of requests per second")?,
+			"id_rate_burst" => self.id_rate_burst = raw.parse("a number of requests")?,
+			"max_failed_attempts" => {
+				self.max_failed_attempts = raw.parse("a number of attempts")?
+			}
+			"lockout_duration" => self.lockout_duration = raw.parse("a number of seconds")?,
+			"trusted_proxies" => {
+				self.trusted_proxies = raw.parse_list("IP addresses, or 'unix'")?
+			}
+			"max_metadata_bytes" => self.max_metadata_bytes = raw.parse("a number of bytes")?,
+			"drain_timeout" => self.drain_timeout = raw.parse("a number of seconds")?,
+			"shutdown_reason" => self.shutdown_reason = Some(value.to_owned()),
+			"shutdown_snapshot" => self.shutdown_snapshot = Some(value.to_owned()),
+			"ping_interval" => self.ping_interval = raw.parse("a number of seconds")?,
+			"pong_timeout" => self.pong_timeout = raw.parse("a number of seconds")?,
+			"max_missed_pongs" => self.max_missed_pongs = raw.parse("a number of pongs")?,
+			"admin_token" => self.admin_token = Some(Secret::new(value.to_owned())),
+			"stats" => self.stats = raw.parse("'admin', 'aggregate', or 'disabled'")?,
+			"stats_interval" => self.stats_interval = raw.parse("a number of seconds")?,
+			"max_message_size" => self.max_message_size = raw.parse("a number of bytes")?,
+			"max_frame_size" => self.max_frame_size = raw.parse("a number of bytes")?,
+			"message_rate_limit" => {
+				self.message_rate_limit = raw.parse("a number of messages per second")?
+			}
+			"message_rate_burst" => self.message_rate_burst = raw.parse("a number of messages")?,
+			"byte_rate_limit" => {
+				self.byte_rate_limit = raw.parse("a number of bytes per second")?
+			}
+			"byte_rate_burst" => self.byte_rate_burst = raw.parse("a number of bytes")?,
+			"total_message_rate_limit" => {
+				self.total_message_rate_limit = raw.parse("a number of messages per second")?
+			}
+			"total_byte_rate_limit" => {
+				self.total_byte_rate_limit = raw.parse("a number of bytes per second")?
+			}
+			"rate_limit_policy" => {
+				self.rate_limit_policy = raw.parse("'drop', 'delay', or 'disconnect'")?
+			}
+			_ => {
+				return Err(ConfigError::UnknownOption(
+					name.to_owned(),
+					origin.to_owned(),
+				))
+			}
 		}
 
-		if matches.is_present("remove") {
-			self.auto_remove = true;
+		Ok(())
+	}
+
+	/// Everywhere the server should listen. If `listen` isn't set, this is just `port` on every
+	/// IPv4 address, with TLS if `secure` is set.
+	pub fn listeners(&self) -> Vec<Listener> {
+		if !self.listen.is_empty() {
+			return self.listen.clone();
 		}
 
-		true
+		let addr = ([0, 0, 0, 0], self.port).into();
+
+		if self.secure {
+			vec![Listener::Tls(addr)]
+		} else {
+			vec![Listener::Tcp(addr)]
+		}
 	}
 
-	pub fn err(err: String) {
-		eprintln!(
-			"\x1b[1m{} \x1b[31m✗\x1b[0m  {}",
-			chrono::Local::now().format("[%H:%M:%S]"),
-			err
-		);
+	/// Checks everything that can only be checked once every source has been applied
+	fn validate(&self) -> Result<(), ConfigError> {
+		if self.secure && (self.key_file.is_none() || self.cert_file.is_none()) {
+			return Err(ConfigError::Invalid(
+				"secure requires both a key_file and a cert_file".to_owned(),
+			));
+		}
+
+		let has_tls = self.listen.iter().any(|l| matches!(l, Listener::Tls(_)));
+
+		if has_tls && (self.key_file.is_none() || self.cert_file.is_none()) {
+			return Err(ConfigError::Invalid(
+				"tls:// listeners require both a key_file and a cert_file".to_owned(),
+			));
+		}
+
+		if self.reap_interval == 0 {
+			return Err(ConfigError::Invalid(
+				"reap_interval must be at least 1 second".to_owned(),
+			));
+		}
+
+		// a close frame can only hold 125 bytes, 2 of which are the close code
+		if matches!(self.shutdown_reason, Some(ref reason) if reason.len() > 123) {
+			return Err(ConfigError::Invalid(
+				"shutdown_reason must be at most 123 bytes long".to_owned(),
+			));
+		}
+
+		if matches!(self.admin_token, Some(ref token) if token.expose().is_empty()) {
+			return Err(ConfigError::Invalid(
+				"admin_token can't be empty".to_owned(),
+			));
+		}
+
+		if self.stats == StatsAccess::Admin && self.admin_token.is_none() {
+			return Err(ConfigError::Invalid(
+				"stats = admin requires an admin_token".to_owned(),
+			));
+		}
+
+		if self.stats_interval == 0 {
+			return Err(ConfigError::Invalid(
+				"stats_interval must be at least 1 second".to_owned(),
+			));
+		}
+
+		if self.max_message_size == 0 || self.max_frame_size == 0 {
+			return Err(ConfigError::Invalid(
+				"max_message_size and max_frame_size must be at least 1 byte".to_owned(),
+			));
+		}
+
+		for (name, secs) in [
+			("ping_interval", self.ping_interval),
+			("pong_timeout", self.pong_timeout),
+		] {
+			if !(1..=MAX_KEEPALIVE_SECS).contains(&secs) {
+				return Err(ConfigError::Invalid(format!(
+					"{} must be from 1 to {} seconds",
+					name, MAX_KEEPALIVE_SECS
+				)));
+			}
+		}
+
+		if self.queue_len == 0 {
+			return Err(ConfigError::Invalid(
+				"queue_len must be at least 1".to_owned(),
+			));
+		}
+
+		if self.secret_key.expose().len() < MIN_SECRET_KEY_LEN {
+			return Err(ConfigError::Invalid(format!(
+				"secret_key must be at least {} bytes long, since it's the salt for hashing keys",
+				MIN_SECRET_KEY_LEN
+			)));
+		}
+
+		// argon2 itself rejects anything less than 8 KiB per lane, and we only ever use one lane
+		if !(8..=MAX_ARGON2_MEMORY).contains(&self.argon2_memory) {
+			return Err(ConfigError::Invalid(format!(
+				"argon2_memory must be between 8 and {} KiB",
+				MAX_ARGON2_MEMORY
+			)));
+		}
+
+		if self.argon2_iterations == 0 {
+			return Err(ConfigError::Invalid(
+				"argon2_iterations must be at least 1".to_owned(),
+			));
+		}
+
+		if self.hash_concurrency == 0 {
+			return Err(ConfigError::Invalid(
+				"hash_concurrency must be at least 1".to_owned(),
+			));
+		}
+
+		// the totals for each registration share the bursts that each connection has
+		for (name, rate, burst_name, burst) in [
+			(
+				"ip_rate_limit",
+				self.ip_rate_limit,
+				"ip_rate_burst",
+				u64::from(self.ip_rate_burst),
+			),
+			(
+				"id_rate_limit",
+				self.id_rate_limit,
+				"id_rate_burst",
+				u64::from(self.id_rate_burst),
+			),
+			(
+				"message_rate_limit",
+				self.message_rate_limit,
+				"message_rate_burst",
+				self.message_rate_burst,
+			),
+			(
+				"byte_rate_limit",
+				self.byte_rate_limit,
+				"byte_rate_burst",
+				self.byte_rate_burst,
+			),
+			(
+				"total_message_rate_limit",
+				self.total_message_rate_limit,
+				"message_rate_burst",
+				self.message_rate_burst,
+			),
+			(
+				"total_byte_rate_limit",
+				self.total_byte_rate_limit,
+				"byte_rate_burst",
+				self.byte_rate_burst,
+			),
+		] {
+			if !(rate >= 0.0 && rate.is_finite()) {
+				return Err(ConfigError::Invalid(format!(
+					"{} must be a positive number, or 0 to disable it",
+					name
+				)));
+			}
+
+			if rate > 0.0 && burst == 0 {
+				return Err(ConfigError::Invalid(format!(
+					"{} must be at least 1, or else everything would be limited",
+					burst_name
+				)));
+			}
+		}
+
+		if let (Some(default), Some(max)) = (self.default_ttl, self.max_ttl) {
+			if default > max {
+				return Err(ConfigError::Invalid(format!(
+					"default_ttl ({}) can't be longer than max_ttl ({})",
+					default, max
+				)));
+			}
+		}
+
+		Ok(())
 	}
+}
 
-	pub fn log(log: String, color: Color) {
-		let col_str = match color {
-			Color::Green => "32",
-			Color::Yellow => "33",
-			Color::Blue => "34",
-			Color::Purple => "35",
-		};
+struct RawValue<'a> {
+	name: &'a str,
+	value: &'a str,
+	origin: &'a str,
+}
 
-		println!(
-			"\x1b[1m{} \x1b[{}m=>\x1b[0m {}",
-			chrono::Local::now().format("[%H:%M:%S]"),
-			col_str,
-			log
-		);
+impl RawValue<'_> {
+	fn parse<T: FromStr>(&self, expected: &'static str) -> Result<T, ConfigError> {
+		self.value.parse().map_err(|_| ConfigError::InvalidValue {
+			name: self.name.to_owned(),
+			value: self.value.to_owned(),
+			origin: self.origin.to_owned(),
+			expected,
+		})
 	}
 
-	pub async fn out_and_vbs() -> (bool, bool) {
-		let conf = crate::CONFIG.read().await;
-		(!conf.quiet, conf.verbose)
+	/// Parses a comma-separated list, which is also what arrays in the config file are turned
+	/// into
+	fn parse_list<T: FromStr>(&self, expected: &'static str) -> Result<Vec<T>, ConfigError> {
+		self.value
+			.split(',')
+			.map(str::trim)
+			.filter(|v| !v.is_empty())
+			.map(|v| {
+				v.parse().map_err(|_| ConfigError::InvalidValue {
+					name: self.name.to_owned(),
+					value: v.to_owned(),
+					origin: self.origin.to_owned(),
+					expected,
+				})
+			})
+			.collect()
 	}
 }
 
-pub enum Color {
-	Green,
-	Yellow,
-	Blue,
-	Purple,
+#[derive(Debug, Error)]
+pub enum ConfigError {
+	#[error("Failed to read config file '{0}': {1}")]
+	ReadFile(String, std::io::Error),
+	#[error("Failed to parse config file '{0}': {1}")]
+	ParseFile(String, toml::de::Error),
+	#[error("Unknown option '{0}' (from {1})")]
+	UnknownOption(String, String),
+	#[error("Invalid value '{value}' for {name} (from {origin}); expected {expected}")]
+	InvalidValue {
+		name: String,
+		value: String,
+		origin: String,
+		expected: &'static str,
+	},
+	#[error("The environment variable {0} isn't valid unicode")]
+	NotUnicode(String),
+	#[error("{0}")]
+	Invalid(String),
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn later_sources_override_conflicting_flags() {
+		let mut conf = Config::default();
+
+		conf.apply("quiet", "true", "config file").unwrap();
+		conf.apply("verbose", "true", "--verbose").unwrap();
+		assert!(conf.verbose && !conf.quiet);
+		assert!(conf.validate().is_ok());
+
+		conf.apply("quiet", "true", "--quiet").unwrap();
+		assert!(conf.quiet && !conf.verbose);
+
+		// turning one of them off doesn't turn the other back on
+		conf.apply("verbose", "false", "WS_ROUTER_VERBOSE").unwrap();
+		assert!(conf.quiet && !conf.verbose);
+	}
+
+	#[test]
+	fn reads_every_kind_of_value_from_a_file() {
+		let path = env::temp_dir().join(format!("ws_router_config_{}.toml", Uuid::new_v4()));
+
+		fs::write(
+			&path,
+			"port = 9000\nauto_remove = true\nmessage_rate_limit = 2.5\nlisten = [\"127.0.0.1:8080\", \"unix:/tmp/ws.sock\"]\n",
+		)
+		.unwrap();
+
+		let mut conf = Config::default();
+		let applied = conf.apply_file(path.to_str().unwrap());
+		let _ = fs::remove_file(&path);
+
+		applied.unwrap();
+		assert_eq!(conf.port, 9000);
+		assert!(conf.auto_remove);
+		assert_eq!(conf.message_rate_limit, 2.5);
+		assert_eq!(conf.listen.len(), 2);
+	}
+
+	#[test]
+	fn rejects_invalid_and_unknown_options() {
+		let mut conf = Config::default();
+
+		assert!(matches!(
+			conf.apply("port", "lots", "--port"),
+			Err(ConfigError::InvalidValue { .. })
+		));
+		assert!(matches!(
+			conf.apply("prot", "8000", "--prot"),
+			Err(ConfigError::UnknownOption(..))
+		));
+	}
+
+	#[test]
+	fn validates_options_that_depend_on_each_other() {
+		let mut conf = Config::default();
+		assert!(conf.validate().is_ok());
+
+		conf.apply("secure", "true", "--secure").unwrap();
+		assert!(conf.validate().is_err());
+
+		let mut conf = Config::default();
+		conf.apply("stats", "admin", "--stats").unwrap();
+		assert!(conf.validate().is_err());
+		conf.apply("admin_token", "token", "--admin_token").unwrap();
+		assert!(conf.validate().is_ok());
+
+		let mut conf = Config::default();
+		conf.apply("max_ttl", "10", "--max_ttl").unwrap();
+		conf.apply("default_ttl", "20", "--default_ttl").unwrap();
+		assert!(conf.validate().is_err());
+
+		let mut conf = Config::default();
+		conf.apply("ip_rate_limit", "-1", "--ip_rate_limit")
+			.unwrap();
+		assert!(conf.validate().is_err());
+
+		let mut conf = Config::default();
+		conf.apply("secret_key", "short", "--secret_key").unwrap();
+		assert!(conf.validate().is_err());
+		conf.apply("secret_key", "longer key", "--secret_key")
+			.unwrap();
+		assert!(conf.validate().is_ok());
+
+		let mut conf = Config::default();
+		conf.argon2_memory = MAX_ARGON2_MEMORY + 1;
+		assert!(conf.validate().is_err());
+	}
+
+	// this is the only test that touches the environment, since every `WS_ROUTER_*` variable is
+	// read at once and tests run in parallel
+	#[cfg(unix)]
+	#[test]
+	fn reads_the_environment_without_panicking_on_non_unicode() {
+		use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+		let invalid = OsStr::from_bytes(&[0xff, 0xfe]);
+
+		env::set_var("NOT_WS_ROUTER", invalid);
+		env::set_var("WS_ROUTER_PORT", "9001");
+
+		let mut conf = Config::default();
+		conf.apply_env().unwrap();
+		assert_eq!(conf.port, 9001);
+
+		env::set_var("WS_ROUTER_PORT", invalid);
+		let result = Config::default().apply_env();
+
+		env::remove_var("WS_ROUTER_PORT");
+		env::remove_var("NOT_WS_ROUTER");
+
+		assert!(matches!(result, Err(ConfigError::NotUnicode(_))));
+	}
 }
@@ -1,9 +1,164 @@
-use crate::sockets::SocketType;
-use futures_util::stream::SplitSink;
+use crate::{
+	metrics,
+	queue::{OutboundQueue, QueuePolicy},
+	sockets::SocketType,
+};
+use chrono::Utc;
+use futures_util::{stream::SplitSink, SinkExt};
+use std::{
+	collections::VecDeque,
+	net::SocketAddr,
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		Arc,
+	},
+};
+use tracing::{debug, error, info_span, Instrument};
 use warp::ws::{Message, WebSocket};
 
 pub struct Connection {
-	pub sender: SplitSink<WebSocket, Message>,
+	pub queue: Arc<OutboundQueue>,
 	pub sock_type: SocketType,
 	pub uuid: String,
+	pub resume_token: Option<String>,
+	/// Whether this connection joined with the registration's host key
+	pub privileged: bool,
+	/// How long it took this connection to answer its last ping, in microseconds, or 0 if it
+	/// hasn't answered one yet
+	pub rtt_micros: AtomicU64,
+	/// Where this connection came from, if it didn't come through a Unix socket
+	pub remote_addr: Option<SocketAddr>,
+	pub connected_at: i64,
+	/// How many bytes of messages this connection has sent to the router
+	pub bytes_received: Arc<AtomicU64>,
+	/// How many messages this connection has sent faster than its rate limits allow
+	pub rate_limited: Arc<AtomicU64>,
+}
+
+impl Connection {
+	/// Creates a connection and spawns the task that writes everything in its queue to
+	/// `sender`
+	pub fn new(
+		sender: SplitSink<WebSocket, Message>,
+		sock_type: SocketType,
+		uuid: String,
+		resume_token: Option<String>,
+		privileged: bool,
+		queue_len: usize,
+		queue_policy: QueuePolicy,
+	) -> Connection {
+		let queue = Arc::new(OutboundQueue::new(queue_len, queue_policy));
+
+		// this is created inside the connection's span, which has its ids once it's added
+		let span = info_span!("writer");
+		tokio::spawn(Connection::write_queue(queue.clone(), sender).instrument(span));
+
+		Connection {
+			queue,
+			sock_type,
+			uuid,
+			resume_token,
+			privileged,
+			rtt_micros: AtomicU64::new(0),
+			remote_addr: None,
+			connected_at: Utc::now().timestamp(),
+			bytes_received: Arc::new(AtomicU64::new(0)),
+			rate_limited: Arc::new(AtomicU64::new(0)),
+		}
+	}
+
+	/// The round-trip time of this connection's last answered ping, in milliseconds
+	pub fn rtt_ms(&self) -> Option<f64> {
+		match self.rtt_micros.load(Ordering::Relaxed) {
+			0 => None,
+			micros => Some(micros as f64 / 1000.0),
+		}
+	}
+
+	/// Queues a message to be sent to this connection. Returns false if it won't be sent.
+	pub fn send(&self, msg: Message) -> bool {
+		self.queue.push(msg)
+	}
+
+	/// Queues a message that's being sent to other connections too. It isn't copied while it's
+	/// queued, but it is copied once for this connection when it's written.
+	pub fn send_shared(&self, msg: Arc<Message>) -> bool {
+		self.queue.push_shared(msg)
+	}
+
+	async fn write_queue(queue: Arc<OutboundQueue>, mut sender: SplitSink<WebSocket, Message>) {
+		while let Some(msg) = queue.pop().await {
+			// warp's messages own their payloads, so writing a message that's shared with other
+			// connections means copying it for this one, which costs one copy per subscriber
+			// of a broadcast. the last connection to write it gets to use the original.
+			let msg = Arc::try_unwrap(msg).unwrap_or_else(|shared| (*shared).clone());
+			let len = msg.as_bytes().len() as u64;
+
+			if let Err(err) = sender.send(msg).await {
+				error!("Failed to send message: {:?}", err);
+				metrics::SEND_FAILURES.inc();
+				break;
+			}
+
+			queue.sent.fetch_add(1, Ordering::Relaxed);
+			queue.sent_bytes.fetch_add(len, Ordering::Relaxed);
+		}
+
+		// make sure nothing else gets queued if we stopped because the socket died
+		queue.close(None);
+
+		match sender.close().await {
+			Ok(_) => debug!("Closed websocket"),
+			Err(err) => debug!("Websocket was already closed: {}", err),
+		}
+	}
+}
+
+/// A connection whose websocket died, but which may still be resumed with its `resume_token`
+/// until the server's resume grace period runs out. Anything that would have been sent to it in
+/// the meantime is buffered so that it can be replayed once it resumes.
+pub struct DetachedConnection {
+	pub uuid: String,
+	pub sock_type: SocketType,
+	pub resume_token: String,
+	pub detached_at: i64,
+	pub buffer: VecDeque<Message>,
+	pub buffered_bytes: usize,
+}
+
+impl DetachedConnection {
+	pub fn new(uuid: String, sock_type: SocketType, resume_token: String, now: i64) -> Self {
+		DetachedConnection {
+			uuid,
+			sock_type,
+			resume_token,
+			detached_at: now,
+			buffer: VecDeque::new(),
+			buffered_bytes: 0,
+		}
+	}
+
+	/// Buffers a message, dropping the oldest buffered messages if that pushes the buffer past
+	/// `max_len` messages or `max_bytes` bytes
+	pub fn buffer(&mut self, msg: Message, max_len: usize, max_bytes: usize) {
+		let len = msg.as_bytes().len();
+
+		// it would just push everything else out and then get dropped itself
+		if len > max_bytes || max_len == 0 {
+			return;
+		}
+
+		self.buffered_bytes += len;
+		self.buffer.push_back(msg);
+
+		while self.buffer.len() > max_len || self.buffered_bytes > max_bytes {
+			if let Some(old) = self.buffer.pop_front() {
+				self.buffered_bytes -= old.as_bytes().len();
+			}
+		}
+	}
+
+	pub fn is_resumable(&self, now: i64, grace: u64) -> bool {
+		self.detached_at + grace as i64 > now
+	}
 }
@@ -0,0 +1,188 @@
+use crate::{admin, register, shutdown::ShuttingDown, sockets};
+use serde::Serialize;
+use std::convert::Infallible;
+use tracing::error;
+use warp::{
+	filters::body::BodyDeserializeError,
+	http::StatusCode,
+	reject::{
+		InvalidHeader, InvalidQuery, LengthRequired, MethodNotAllowed, MissingHeader,
+		PayloadTooLarge, UnsupportedMediaType,
+	},
+	Rejection, Reply,
+};
+
+/// Every error that a request can fail with, in the shape that it's sent back to the client
+#[derive(Debug)]
+pub struct ApiError {
+	pub status: StatusCode,
+	pub code: &'static str,
+	pub message: String,
+	pub retry_after: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+	code: &'a str,
+	message: &'a str,
+}
+
+impl ApiError {
+	pub fn new(status: StatusCode, code: &'static str, message: impl ToString) -> ApiError {
+		ApiError {
+			status,
+			code,
+			message: message.to_string(),
+			retry_after: None,
+		}
+	}
+
+	pub fn into_response(self) -> warp::reply::Response {
+		let body = ErrorBody {
+			code: self.code,
+			message: &self.message,
+		};
+
+		let mut res =
+			warp::reply::with_status(warp::reply::json(&body), self.status).into_response();
+
+		if let Some(secs) = self.retry_after {
+			res.headers_mut().insert("retry-after", secs.into());
+		}
+
+		res
+	}
+}
+
+impl From<&register::Rejections> for ApiError {
+	fn from(rejection: &register::Rejections) -> ApiError {
+		let mut err = ApiError::new(rejection.status(), rejection.code(), rejection);
+
+		if let register::Rejections::TooManyRequests { retry_after } = rejection {
+			err.retry_after = Some(*retry_after);
+		}
+
+		err
+	}
+}
+
+impl From<&sockets::Rejections> for ApiError {
+	fn from(rejection: &sockets::Rejections) -> ApiError {
+		ApiError::new(rejection.status(), rejection.code(), rejection)
+	}
+}
+
+impl From<&admin::Rejections> for ApiError {
+	fn from(rejection: &admin::Rejections) -> ApiError {
+		ApiError::new(rejection.status(), rejection.code(), rejection)
+	}
+}
+
+impl From<&Rejection> for ApiError {
+	fn from(err: &Rejection) -> ApiError {
+		if let Some(rejection) = err.find::<register::Rejections>() {
+			rejection.into()
+		} else if let Some(rejection) = err.find::<sockets::Rejections>() {
+			rejection.into()
+		} else if let Some(rejection) = err.find::<admin::Rejections>() {
+			rejection.into()
+		} else if let Some(e) = err.find::<ShuttingDown>() {
+			ApiError::new(StatusCode::SERVICE_UNAVAILABLE, "shutting_down", e)
+		} else if err.is_not_found() {
+			ApiError::new(
+				StatusCode::NOT_FOUND,
+				"not_found",
+				"The registration or path does not exist",
+			)
+		} else if let Some(e) = err.find::<BodyDeserializeError>() {
+			ApiError::new(StatusCode::BAD_REQUEST, "invalid_body", e)
+		} else if let Some(e) = err.find::<InvalidQuery>() {
+			ApiError::new(StatusCode::BAD_REQUEST, "invalid_query", e)
+		} else if let Some(e) = err.find::<MissingHeader>() {
+			ApiError::new(StatusCode::BAD_REQUEST, "invalid_request", e)
+		} else if let Some(e) = err.find::<InvalidHeader>() {
+			ApiError::new(StatusCode::BAD_REQUEST, "invalid_request", e)
+		} else if let Some(e) = err.find::<LengthRequired>() {
+			ApiError::new(StatusCode::LENGTH_REQUIRED, "invalid_request", e)
+		} else if let Some(e) = err.find::<PayloadTooLarge>() {
+			ApiError::new(StatusCode::PAYLOAD_TOO_LARGE, "payload_too_large", e)
+		} else if let Some(e) = err.find::<UnsupportedMediaType>() {
+			ApiError::new(StatusCode::UNSUPPORTED_MEDIA_TYPE, "invalid_request", e)
+		} else if let Some(e) = err.find::<MethodNotAllowed>() {
+			ApiError::new(StatusCode::METHOD_NOT_ALLOWED, "method_not_allowed", e)
+		} else {
+			error!("Unhandled rejection: {:?}", err);
+			ApiError::new(
+				StatusCode::INTERNAL_SERVER_ERROR,
+				"internal_error",
+				"Something went wrong on the server",
+			)
+		}
+	}
+}
+
+/// Turns every rejection into a JSON response of the form `{"code": ..., "message": ...}`, with
+/// the right status code, so that clients never get warp's generic "Unhandled rejection"
+pub async fn recover(err: Rejection) -> Result<impl Reply, Infallible> {
+	Ok(ApiError::from(&err).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use serde_json::{json, Value};
+
+	async fn respond(err: Rejection) -> (StatusCode, Option<String>, Value) {
+		let res = recover(err).await.unwrap().into_response();
+		let status = res.status();
+		let retry_after = res
+			.headers()
+			.get("retry-after")
+			.map(|v| v.to_str().unwrap().to_owned());
+		let body = warp::hyper::body::to_bytes(res.into_body()).await.unwrap();
+
+		(status, retry_after, serde_json::from_slice(&body).unwrap())
+	}
+
+	#[tokio::test]
+	async fn rejections_keep_their_codes() {
+		let (status, retry_after, body) =
+			respond(warp::reject::custom(register::Rejections::TTLTooLong)).await;
+
+		assert_eq!(status, StatusCode::BAD_REQUEST);
+		assert_eq!(retry_after, None);
+		assert_eq!(body["code"], "ttl_too_long");
+
+		let (status, _, body) =
+			respond(warp::reject::custom(sockets::Rejections::IncorrectKey)).await;
+
+		assert_eq!(status, StatusCode::UNAUTHORIZED);
+		assert_eq!(body["code"], "invalid_key");
+	}
+
+	#[tokio::test]
+	async fn too_many_requests_says_when_to_retry() {
+		let (status, retry_after, body) = respond(warp::reject::custom(
+			register::Rejections::TooManyRequests { retry_after: 7 },
+		))
+		.await;
+
+		assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+		assert_eq!(retry_after.as_deref(), Some("7"));
+		assert_eq!(body["code"], "too_many_requests");
+	}
+
+	#[tokio::test]
+	async fn warps_own_rejections_are_json_too() {
+		let (status, _, body) = respond(warp::reject::not_found()).await;
+
+		assert_eq!(status, StatusCode::NOT_FOUND);
+		assert_eq!(
+			body,
+			json!({
+				"code": "not_found",
+				"message": "The registration or path does not exist"
+			})
+		);
+	}
+}
@@ -0,0 +1,204 @@
+use crate::{config::Config, CONFIG};
+use chrono::Utc;
+use lazy_static::lazy_static;
+use std::{
+	str::FromStr,
+	sync::{Mutex, OnceLock},
+};
+use thiserror::Error;
+use tokio::sync::Semaphore;
+use tracing::{error, warn};
+use uuid::Uuid;
+
+static PERMITS: OnceLock<Semaphore> = OnceLock::new();
+
+lazy_static! {
+	// keys the digests in `HashedKey`'s cache, so that they're worthless outside this process
+	static ref CACHE_KEY: [u8; 32] = {
+		let mut key = [0; 32];
+		key[..16].copy_from_slice(Uuid::new_v4().as_bytes());
+		key[16..].copy_from_slice(Uuid::new_v4().as_bytes());
+		key
+	};
+}
+
+/// Allows up to `concurrency` keys to be hashed or verified at once. Only the first call does
+/// anything, since the semaphore can't be swapped out once keys are being hashed with it.
+pub fn init(concurrency: usize) {
+	if PERMITS.set(Semaphore::new(concurrency)).is_err() {
+		warn!("The hashing pool was already set up; ignoring hash_concurrency");
+	}
+}
+
+// anything that's hashed before `init` gets the default concurrency, instead of waiting forever
+fn permits() -> &'static Semaphore {
+	PERMITS.get_or_init(|| Semaphore::new(Config::default().hash_concurrency))
+}
+
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub enum HashVariant {
+	Argon2d,
+	Argon2i,
+	Argon2id,
+}
+
+impl FromStr for HashVariant {
+	type Err = ();
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"argon2d" => Ok(HashVariant::Argon2d),
+			"argon2i" => Ok(HashVariant::Argon2i),
+			"argon2id" => Ok(HashVariant::Argon2id),
+			_ => Err(()),
+		}
+	}
+}
+
+impl From<HashVariant> for argon2::Variant {
+	fn from(variant: HashVariant) -> argon2::Variant {
+		match variant {
+			HashVariant::Argon2d => argon2::Variant::Argon2d,
+			HashVariant::Argon2i => argon2::Variant::Argon2i,
+			HashVariant::Argon2id => argon2::Variant::Argon2id,
+		}
+	}
+}
+
+#[derive(Debug, Error)]
+pub enum HashError {
+	#[error("argon2 failed: {0}")]
+	Argon2(#[from] argon2::Error),
+	#[error("the hashing task failed: {0}")]
+	Task(#[from] tokio::task::JoinError),
+	#[error("the hashing pool was shut down")]
+	Closed,
+}
+
+/// Hashes `key` with the server's argon2 parameters. Hashing is slow on purpose, so it runs on
+/// tokio's blocking pool, with at most `hash_concurrency` keys being hashed or verified at once,
+/// instead of stalling the async workers that forward messages.
+pub async fn hash(key: String, salt: Vec<u8>) -> Result<String, HashError> {
+	let conf = CONFIG.read().await;
+	let (variant, mem_cost, time_cost) = (
+		conf.argon2_variant.into(),
+		conf.argon2_memory,
+		conf.argon2_iterations,
+	);
+	drop(conf);
+
+	let _permit = permits().acquire().await.map_err(|_| HashError::Closed)?;
+
+	let hashed = tokio::task::spawn_blocking(move || {
+		let config = argon2::Config {
+			variant,
+			mem_cost,
+			time_cost,
+			..argon2::Config::default()
+		};
+
+		argon2::hash_encoded(key.as_bytes(), &salt, &config)
+	})
+	.await??;
+
+	Ok(hashed)
+}
+
+/// Checks `key` against an encoded hash, on the same pool as `hash`. The parameters are read
+/// from the hash itself, so changing them doesn't break registrations hashed before the change.
+pub async fn verify(encoded: String, key: String) -> Result<bool, HashError> {
+	let _permit = permits().acquire().await.map_err(|_| HashError::Closed)?;
+
+	let verified =
+		tokio::task::spawn_blocking(move || argon2::verify_encoded(&encoded, key.as_bytes()))
+			.await??;
+
+	Ok(verified)
+}
+
+/// An argon2 hash of one of a registration's keys, which remembers the last key that was
+/// verified against it for `verify_cache_ttl` seconds. That way a burst of connections
+/// reconnecting with the right key only has to be hashed once, while wrong keys still always go
+/// through argon2.
+pub struct HashedKey {
+	pub hash: String,
+	verified: Mutex<Option<(blake2b_simd::Hash, i64)>>,
+}
+
+impl HashedKey {
+	pub fn new(hash: String) -> HashedKey {
+		HashedKey {
+			hash,
+			verified: Mutex::new(None),
+		}
+	}
+
+	/// Checks `key` against this hash. Failing to check it counts as it not matching, since
+	/// there's nothing the client could do about it anyways.
+	pub async fn verify(&self, key: &str) -> bool {
+		let cache_ttl = CONFIG.read().await.verify_cache_ttl as i64;
+		let now = Utc::now().timestamp();
+		let digest = blake2b_simd::Params::new()
+			.key(&*CACHE_KEY)
+			.hash(key.as_bytes());
+
+		// `blake2b_simd::Hash` compares in constant time
+		if let Ok(verified) = self.verified.lock() {
+			if let Some((ref cached, at)) = *verified {
+				if *cached == digest && now < at + cache_ttl {
+					return true;
+				}
+			}
+		}
+
+		let matches = verify(self.hash.to_owned(), key.to_owned())
+			.await
+			.unwrap_or_else(|err| {
+				error!("Failed to verify key: {}", err);
+				false
+			});
+
+		if matches && cache_ttl > 0 {
+			if let Ok(mut verified) = self.verified.lock() {
+				*verified = Some((digest, now));
+			}
+		}
+
+		matches
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const SALT: &[u8] = b"longsecretkey123";
+
+	#[test]
+	fn variant_parses() {
+		assert_eq!("argon2id".parse(), Ok(HashVariant::Argon2id));
+		assert_eq!("argon2i".parse(), Ok(HashVariant::Argon2i));
+		assert_eq!("argon2d".parse(), Ok(HashVariant::Argon2d));
+		assert!("bcrypt".parse::<HashVariant>().is_err());
+	}
+
+	#[tokio::test]
+	async fn verifies_only_the_right_key() {
+		let hashed = hash("key".to_owned(), SALT.to_vec()).await.unwrap();
+		let mut key = HashedKey::new(hashed);
+
+		assert!(key.verify("key").await);
+		assert!(!key.verify("wrong").await);
+
+		// argon2 can't read this, so the right key can only match it from the cache, and the
+		// wrong one never does
+		key.hash = "not a hash".to_owned();
+		assert!(key.verify("key").await);
+		assert!(!key.verify("wrong").await);
+	}
+
+	#[tokio::test]
+	async fn unreadable_hashes_never_match() {
+		assert!(!HashedKey::new("not a hash".to_owned()).verify("").await);
+	}
+}
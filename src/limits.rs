@@ -0,0 +1,412 @@
+use crate::{metrics, register::Rejections, CONFIG};
+use chrono::Utc;
+use lazy_static::lazy_static;
+use std::{
+	collections::HashMap,
+	hash::Hash,
+	net::{IpAddr, SocketAddr},
+	str::FromStr,
+	sync::{Mutex, MutexGuard},
+	time::Instant,
+};
+use tracing::warn;
+use warp::{Filter, Rejection};
+
+lazy_static! {
+	static ref IP_BUCKETS: RateLimiter<IpAddr> = RateLimiter::new();
+	static ref ID_BUCKETS: RateLimiter<String> = RateLimiter::new();
+	static ref FAILURES: Mutex<HashMap<String, Failures>> = Mutex::new(HashMap::new());
+}
+
+/// A set of token buckets, one for each key. Every request takes a token from its key's bucket,
+/// and each bucket refills at `rate` tokens per second, up to `burst` tokens.
+struct RateLimiter<K> {
+	buckets: Mutex<HashMap<K, Bucket>>,
+}
+
+/// A single token bucket, which refills at some rate up to some burst. Connections keep their
+/// own, so they don't need a `RateLimiter`.
+pub struct Bucket {
+	tokens: f64,
+	updated: Instant,
+}
+
+impl Bucket {
+	/// A bucket that starts out full
+	pub fn new(burst: f64) -> Bucket {
+		Bucket {
+			tokens: burst,
+			updated: Instant::now(),
+		}
+	}
+
+	/// Adds the tokens that have accumulated since the last time this was called
+	pub fn refill(&mut self, rate: f64, burst: f64) {
+		let now = Instant::now();
+		let elapsed = now.duration_since(self.updated).as_secs_f64();
+
+		self.tokens = (self.tokens + elapsed * rate).min(burst);
+		self.updated = now;
+	}
+
+	/// How many seconds it'll be until there are `amount` tokens to take, or 0 if there
+	/// already are
+	pub fn wait_for(&self, amount: f64, rate: f64) -> f64 {
+		if self.tokens >= amount {
+			0.0
+		} else {
+			(amount - self.tokens) / rate
+		}
+	}
+
+	pub fn take(&mut self, amount: f64) {
+		self.tokens -= amount;
+	}
+}
+
+impl<K: Hash + Eq> RateLimiter<K> {
+	fn new() -> RateLimiter<K> {
+		RateLimiter {
+			buckets: Mutex::new(HashMap::new()),
+		}
+	}
+
+	// a panic while holding this lock can't leave the buckets in an invalid state, so there's no
+	// reason to stop using them if that happens
+	fn buckets(&self) -> MutexGuard<'_, HashMap<K, Bucket>> {
+		self.buckets.lock().unwrap_or_else(|p| p.into_inner())
+	}
+
+	/// Takes a token from `key`'s bucket, or returns how many seconds it'll be until there is
+	/// one to take
+	fn take(&self, key: K, rate: f64, burst: u32) -> Result<(), u64> {
+		let mut buckets = self.buckets();
+
+		let bucket = buckets
+			.entry(key)
+			.or_insert_with(|| Bucket::new(burst as f64));
+
+		bucket.refill(rate, burst as f64);
+
+		match bucket.wait_for(1.0, rate) {
+			wait if wait > 0.0 => Err(wait.ceil().max(1.0) as u64),
+			_ => {
+				bucket.take(1.0);
+				Ok(())
+			}
+		}
+	}
+
+	/// Forgets every bucket that would have refilled by now, since those are the same as a
+	/// bucket that was never used
+	fn prune(&self, rate: f64, burst: u32) {
+		let now = Instant::now();
+
+		self.buckets().retain(|_, bucket| {
+			let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+			bucket.tokens + elapsed * rate < burst as f64
+		});
+	}
+}
+
+/// The failed attempts to verify a registration's keys since the last successful one
+struct Failures {
+	count: u32,
+	last: i64,
+	locked_until: Option<i64>,
+}
+
+fn failures() -> MutexGuard<'static, HashMap<String, Failures>> {
+	FAILURES.lock().unwrap_or_else(|p| p.into_inner())
+}
+
+fn too_many_requests(retry_after: u64) -> Rejection {
+	metrics::reject(Rejections::TooManyRequests { retry_after })
+}
+
+/// A reverse proxy whose `X-Forwarded-For` header is trusted to say where a request really came
+/// from
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub enum TrustedProxy {
+	Ip(IpAddr),
+	/// Whatever is on the other end of the server's Unix sockets
+	Unix,
+}
+
+impl FromStr for TrustedProxy {
+	type Err = ();
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"unix" => Ok(TrustedProxy::Unix),
+			ip => ip.parse().map(TrustedProxy::Ip).map_err(|_| ()),
+		}
+	}
+}
+
+/// The address that a request came from. Requests from one of `trusted_proxies` are counted as
+/// coming from the address in their `X-Forwarded-For` header instead, since otherwise every
+/// request through the proxy would look like it came from the proxy itself.
+pub fn client_addr() -> impl Filter<Extract = (Option<SocketAddr>,), Error = Rejection> + Clone {
+	warp::addr::remote()
+		.and(warp::header::optional::<String>("x-forwarded-for"))
+		.and_then(|peer, forwarded: Option<String>| async move {
+			let trusted = CONFIG.read().await.trusted_proxies.clone();
+			Ok::<_, Rejection>(forwarded_for(peer, forwarded.as_deref(), &trusted))
+		})
+}
+
+/// Works out where a request from `peer` really came from. Each proxy adds the address it got the
+/// request from to the end of `X-Forwarded-For`, so the client is the last address that isn't a
+/// trusted proxy; anything before that could have been made up by the client. Addresses that
+/// come from the header have no port, so they get port 0.
+fn forwarded_for(
+	peer: Option<SocketAddr>,
+	forwarded: Option<&str>,
+	trusted: &[TrustedProxy],
+) -> Option<SocketAddr> {
+	let is_trusted = |ip: Option<IpAddr>| match ip {
+		Some(ip) => trusted.contains(&TrustedProxy::Ip(ip)),
+		None => trusted.contains(&TrustedProxy::Unix),
+	};
+
+	if !is_trusted(peer.map(|p| p.ip())) {
+		return peer;
+	}
+
+	let mut client = peer;
+
+	for hop in forwarded.unwrap_or_default().rsplit(',') {
+		let ip = match hop.trim().parse::<IpAddr>() {
+			Ok(ip) => ip,
+			// some proxies include the port, which we don't need
+			Err(_) => match hop.trim().parse::<SocketAddr>() {
+				Ok(addr) => addr.ip(),
+				Err(_) => break,
+			},
+		};
+
+		client = Some(SocketAddr::new(ip, 0));
+
+		if !is_trusted(Some(ip)) {
+			break;
+		}
+	}
+
+	client
+}
+
+/// Rate limits requests by the IP address they came from, as worked out by `client_addr`.
+/// Requests that we don't know the address of, like ones over a Unix socket from a proxy that
+/// isn't trusted, aren't limited.
+pub async fn check_ip(addr: Option<SocketAddr>) -> Result<(), Rejection> {
+	let conf = CONFIG.read().await;
+	let (rate, burst) = (conf.ip_rate_limit, conf.ip_rate_burst);
+	drop(conf);
+
+	match addr {
+		Some(addr) if rate > 0.0 => IP_BUCKETS.take(addr.ip(), rate, burst).map_err(|secs| {
+			warn!(ip = %addr.ip(), "Rate limiting requests from this address");
+			too_many_requests(secs)
+		}),
+		_ => Ok(()),
+	}
+}
+
+/// Rate limits requests for a single registration, and rejects them outright while it's locked
+/// out for too many failed attempts at its keys
+pub async fn check_id(id: &str) -> Result<(), Rejection> {
+	let conf = CONFIG.read().await;
+	let (rate, burst) = (conf.id_rate_limit, conf.id_rate_burst);
+	drop(conf);
+
+	let now = Utc::now().timestamp();
+
+	if let Some(Failures {
+		locked_until: Some(until),
+		..
+	}) = failures().get(id)
+	{
+		if *until > now {
+			warn!("Rejecting because this registration is locked out");
+			return Err(too_many_requests((*until - now) as u64));
+		}
+	}
+
+	if rate > 0.0 {
+		ID_BUCKETS
+			.take(id.to_owned(), rate, burst)
+			.map_err(|secs| {
+				warn!("Rate limiting requests for this registration");
+				too_many_requests(secs)
+			})?;
+	}
+
+	Ok(())
+}
+
+/// Records a failed attempt at one of a registration's keys, locking it out for
+/// `lockout_duration` seconds once there have been `max_failed_attempts` of them in a row
+pub async fn record_failure(id: &str) {
+	let conf = CONFIG.read().await;
+	let (max_attempts, duration) = (conf.max_failed_attempts, conf.lockout_duration as i64);
+	drop(conf);
+
+	if max_attempts == 0 {
+		return;
+	}
+
+	let now = Utc::now().timestamp();
+	let mut failures = failures();
+
+	let entry = failures.entry(id.to_owned()).or_insert(Failures {
+		count: 0,
+		last: now,
+		locked_until: None,
+	});
+
+	// failures that are older than a lockout would have been forgotten by now anyways
+	if now - entry.last > duration {
+		entry.count = 0;
+	}
+
+	entry.count += 1;
+	entry.last = now;
+
+	if entry.count >= max_attempts {
+		warn!(
+			"Locking registration out for {}s after {} failed attempts",
+			duration, entry.count
+		);
+
+		entry.count = 0;
+		entry.locked_until = Some(now + duration);
+	}
+}
+
+pub fn record_success(id: &str) {
+	failures().remove(id);
+}
+
+/// Forgets everything that no longer affects whether a request is limited, so that clients
+/// that went away don't take up memory forever
+pub async fn prune() {
+	let conf = CONFIG.read().await;
+	IP_BUCKETS.prune(conf.ip_rate_limit, conf.ip_rate_burst);
+	ID_BUCKETS.prune(conf.id_rate_limit, conf.id_rate_burst);
+	let duration = conf.lockout_duration as i64;
+	drop(conf);
+
+	let now = Utc::now().timestamp();
+
+	failures().retain(|_, f| {
+		matches!(f.locked_until, Some(until) if until > now) || now - f.last <= duration
+	});
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn addr(s: &str) -> Option<SocketAddr> {
+		Some(s.parse().unwrap())
+	}
+
+	#[test]
+	fn bucket_allows_its_burst_and_then_waits() {
+		let limiter = RateLimiter::new();
+
+		assert!(limiter.take("a", 1.0, 2).is_ok());
+		assert!(limiter.take("a", 1.0, 2).is_ok());
+		assert_eq!(limiter.take("a", 1.0, 2), Err(1));
+
+		// every key has a bucket of its own
+		assert!(limiter.take("b", 1.0, 2).is_ok());
+	}
+
+	#[test]
+	fn bucket_waits_for_as_many_tokens_as_it_needs() {
+		let mut bucket = Bucket::new(10.0);
+
+		assert_eq!(bucket.wait_for(10.0, 5.0), 0.0);
+		bucket.take(10.0);
+		assert_eq!(bucket.wait_for(5.0, 5.0), 1.0);
+	}
+
+	#[test]
+	fn prune_forgets_only_full_buckets() {
+		let limiter = RateLimiter::new();
+
+		limiter.take("a", 1.0, 5).unwrap();
+		limiter.prune(1.0, 5);
+		assert_eq!(limiter.buckets().len(), 1);
+
+		limiter.buckets().get_mut("a").unwrap().tokens = 5.0;
+		limiter.prune(1.0, 5);
+		assert!(limiter.buckets().is_empty());
+	}
+
+	#[tokio::test]
+	async fn locks_out_after_too_many_failures_in_a_row() {
+		let id = "lockout_test";
+		let max = CONFIG.read().await.max_failed_attempts;
+
+		for _ in 1..max {
+			record_failure(id).await;
+		}
+		assert!(check_id(id).await.is_ok());
+
+		record_success(id);
+		for _ in 1..max {
+			record_failure(id).await;
+		}
+		assert!(check_id(id).await.is_ok());
+
+		record_failure(id).await;
+		assert!(check_id(id).await.is_err());
+	}
+
+	#[test]
+	fn untrusted_peers_are_who_they_say() {
+		let peer = addr("203.0.113.5:4000");
+
+		assert_eq!(forwarded_for(peer, Some("198.51.100.1"), &[]), peer);
+		assert_eq!(forwarded_for(None, Some("198.51.100.1"), &[]), None);
+	}
+
+	#[test]
+	fn trusted_proxies_pass_on_the_client() {
+		let proxy = TrustedProxy::Ip("127.0.0.1".parse().unwrap());
+		let peer = addr("127.0.0.1:4000");
+
+		assert_eq!(
+			forwarded_for(peer, Some("198.51.100.1"), &[proxy]),
+			addr("198.51.100.1:0")
+		);
+
+		// anything before the last untrusted address could have come from the client
+		assert_eq!(
+			forwarded_for(peer, Some("10.0.0.1, 198.51.100.1, 127.0.0.1"), &[proxy]),
+			addr("198.51.100.1:0")
+		);
+
+		assert_eq!(
+			forwarded_for(peer, Some("[2001:db8::1]:5000"), &[proxy]),
+			addr("[2001:db8::1]:0")
+		);
+
+		// without the header, the request is from the proxy itself
+		assert_eq!(forwarded_for(peer, None, &[proxy]), peer);
+		assert_eq!(forwarded_for(peer, Some("garbage"), &[proxy]), peer);
+	}
+
+	#[test]
+	fn unix_sockets_can_be_trusted() {
+		assert_eq!(
+			forwarded_for(None, Some("198.51.100.1"), &[TrustedProxy::Unix]),
+			addr("198.51.100.1:0")
+		);
+		assert_eq!("unix".parse(), Ok(TrustedProxy::Unix));
+		assert!("proxy".parse::<TrustedProxy>().is_err());
+	}
+}
@@ -0,0 +1,196 @@
+use futures_util::Stream;
+use rustls::{internal::pemfile, NoClientAuth, ServerConfig};
+use std::{
+	fmt, fs, io,
+	net::SocketAddr,
+	path::{Path, PathBuf},
+	str::FromStr,
+};
+
+/// Somewhere that the server accepts connections
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub enum Listener {
+	/// Plaintext HTTP on a TCP address, written as `1.2.3.4:8741`, `[::]:8741`, or with a
+	/// `tcp://` in front
+	Tcp(SocketAddr),
+	/// HTTPS on a TCP address, written as `tls://1.2.3.4:443`. This uses the server's
+	/// `key_file` and `cert_file`.
+	Tls(SocketAddr),
+	/// Plaintext HTTP on a Unix domain socket, written as `unix:/path/to/socket`
+	Unix(PathBuf),
+}
+
+impl FromStr for Listener {
+	type Err = ();
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		if let Some(path) = s.strip_prefix("unix:") {
+			if path.is_empty() {
+				Err(())
+			} else {
+				Ok(Listener::Unix(PathBuf::from(path)))
+			}
+		} else if let Some(addr) = s.strip_prefix("tls://") {
+			addr.parse().map(Listener::Tls).map_err(|_| ())
+		} else {
+			let addr = s.strip_prefix("tcp://").unwrap_or(s);
+			addr.parse().map(Listener::Tcp).map_err(|_| ())
+		}
+	}
+}
+
+impl fmt::Display for Listener {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Listener::Tcp(addr) => write!(f, "tcp://{}", addr),
+			Listener::Tls(addr) => write!(f, "tls://{}", addr),
+			Listener::Unix(path) => write!(f, "unix:{}", path.display()),
+		}
+	}
+}
+
+/// Starts listening on a Unix domain socket at `path`, replacing any socket that was left
+/// behind there by a server that didn't shut down cleanly
+#[cfg(unix)]
+pub fn bind_unix(
+	path: &Path,
+) -> io::Result<impl Stream<Item = io::Result<tokio::net::UnixStream>> + Send + 'static> {
+	use std::os::unix::fs::FileTypeExt;
+
+	// never delete something that isn't a socket, in case the path is a mistake
+	if let Ok(meta) = std::fs::symlink_metadata(path) {
+		if meta.file_type().is_socket() {
+			std::fs::remove_file(path)?;
+		}
+	}
+
+	let listener = tokio::net::UnixListener::bind(path)?;
+
+	Ok(Box::pin(futures_util::stream::unfold(
+		listener,
+		|listener| async move {
+			let accepted = listener.accept().await.map(|(stream, _)| stream);
+			Some((accepted, listener))
+		},
+	)))
+}
+
+#[cfg(not(unix))]
+pub fn bind_unix(
+	_path: &Path,
+) -> io::Result<futures_util::stream::Empty<io::Result<tokio::net::TcpStream>>> {
+	Err(io::Error::new(
+		io::ErrorKind::Unsupported,
+		"Unix sockets aren't supported on this platform",
+	))
+}
+
+/// Loads the key and certificate for TLS listeners the same way that warp does, so that a
+/// missing or invalid one is an error here instead of a panic inside warp
+pub fn check_tls_files(key_file: &str, cert_file: &str) -> Result<(), String> {
+	let key = read_tls_file("key_file", key_file)?;
+	let cert = read_tls_file("cert_file", cert_file)?;
+
+	let certs = match pemfile::certs(&mut cert.as_slice()) {
+		Ok(certs) if !certs.is_empty() => certs,
+		_ => return Err(format!("cert_file '{}' has no PEM certificates", cert_file)),
+	};
+
+	// warp uses the first PKCS#8 key, or the first RSA key if there aren't any of those
+	let key = pemfile::pkcs8_private_keys(&mut key.as_slice())
+		.ok()
+		.filter(|keys| !keys.is_empty())
+		.or_else(|| pemfile::rsa_private_keys(&mut key.as_slice()).ok())
+		.and_then(|keys| keys.into_iter().next())
+		.ok_or_else(|| format!("key_file '{}' has no PEM PKCS#8 or RSA keys", key_file))?;
+
+	ServerConfig::new(NoClientAuth::new())
+		.set_single_cert(certs, key)
+		.map_err(|err| format!("Can't use key_file '{}': {}", key_file, err))
+}
+
+fn read_tls_file(name: &str, path: &str) -> Result<Vec<u8>, String> {
+	match fs::read(path) {
+		Ok(contents) if contents.is_empty() => Err(format!("{} '{}' is empty", name, path)),
+		Ok(contents) => Ok(contents),
+		Err(err) => Err(format!("Failed to read {} '{}': {}", name, path, err)),
+	}
+}
+
+/// Makes sure that a TCP address can be listened on, for servers (like warp's TLS server) that
+/// panic instead of returning an error if they can't bind. The address is let go again right
+/// away, so this can't catch something else taking it in the meantime.
+pub fn check_addr(addr: SocketAddr) -> io::Result<()> {
+	std::net::TcpListener::bind(addr).map(drop)
+}
+
+/// Removes the socket files of every Unix socket listener, once the server is done with them
+pub fn cleanup(listeners: &[Listener]) {
+	for listener in listeners {
+		if let Listener::Unix(path) = listener {
+			if let Err(err) = std::fs::remove_file(path) {
+				tracing::debug!("Failed to remove socket '{}': {}", path.display(), err);
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parses_every_kind_of_listener() {
+		assert_eq!(
+			"127.0.0.1:8080".parse(),
+			Ok(Listener::Tcp(([127, 0, 0, 1], 8080).into()))
+		);
+		assert_eq!(
+			"tcp://[::1]:8080"
+				.parse::<Listener>()
+				.map(|l| l.to_string()),
+			Ok("tcp://[::1]:8080".to_owned())
+		);
+		assert_eq!(
+			"tls://0.0.0.0:443".parse(),
+			Ok(Listener::Tls(([0, 0, 0, 0], 443).into()))
+		);
+		assert_eq!(
+			"unix:/run/ws.sock".parse(),
+			Ok(Listener::Unix(PathBuf::from("/run/ws.sock")))
+		);
+
+		for invalid in ["unix:", "tls://localhost", "8080", "http://127.0.0.1:80"] {
+			assert!(invalid.parse::<Listener>().is_err(), "{}", invalid);
+		}
+	}
+
+	#[test]
+	fn tls_files_must_exist() {
+		let missing = std::env::temp_dir().join(format!("ws_router_{}.pem", uuid::Uuid::new_v4()));
+		let missing = missing.to_str().unwrap();
+
+		assert!(check_tls_files(missing, missing)
+			.unwrap_err()
+			.contains("key_file"));
+	}
+
+	#[test]
+	fn tls_files_must_hold_a_key_and_certificate() {
+		let path = std::env::temp_dir().join(format!("ws_router_{}.pem", uuid::Uuid::new_v4()));
+		fs::write(&path, "not a certificate").unwrap();
+		let file = path.to_str().unwrap();
+
+		let err = check_tls_files(file, file).unwrap_err();
+		fs::remove_file(&path).unwrap();
+		assert!(err.contains("cert_file"), "{}", err);
+	}
+
+	#[test]
+	fn addresses_in_use_are_errors() {
+		let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+
+		assert!(check_addr(taken.local_addr().unwrap()).is_err());
+		assert!(check_addr(([127, 0, 0, 1], 0).into()).is_ok());
+	}
+}
@@ -0,0 +1,167 @@
+use crate::{
+	limits,
+	register::{Registration, RegistrationType},
+	Registrations,
+};
+use serde::Deserialize;
+use std::net::SocketAddr;
+use tracing::info;
+use warp::{Rejection, Reply};
+
+/// How many lobbies are listed when the request doesn't say
+const DEFAULT_LIMIT: usize = 20;
+/// The most lobbies that can be listed at once
+const MAX_LIMIT: usize = 100;
+
+#[derive(Deserialize)]
+pub struct LobbiesRequest {
+	pub tag: Option<String>,
+	pub offset: Option<usize>,
+	pub limit: Option<usize>,
+}
+
+/// Lists every lobby and hostclient registration that was created with `public`, ordered by id
+/// so that paging through them stays consistent as long as none are added or removed. Broadcast
+/// registrations aren't something that players join, so they're never listed.
+pub async fn return_lobbies(
+	req: LobbiesRequest,
+	addr: Option<SocketAddr>,
+	rgs: Registrations,
+) -> Result<impl Reply, Rejection> {
+	info!("Listing public lobbies...");
+
+	limits::check_ip(addr).await?;
+
+	let regs = rgs.read().await;
+
+	let mut public = Vec::new();
+	let joinable = |r: &&Registration| {
+		matches!(
+			r.reg_type,
+			RegistrationType::Lobby | RegistrationType::HostClient
+		)
+	};
+
+	for reg in regs.values().filter(|r| r.public).filter(joinable) {
+		if *reg.destroy.read().await {
+			continue;
+		}
+
+		if let Some(ref tag) = req.tag {
+			if !reg.metadata.tags.contains(tag) {
+				continue;
+			}
+		}
+
+		public.push(reg);
+	}
+
+	public.sort_by(|a, b| a.uuid.cmp(&b.uuid));
+
+	let total = public.len();
+	let offset = req.offset.unwrap_or(0);
+	let limit = req.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+
+	let mut lobbies = Vec::new();
+	for reg in public.into_iter().skip(offset).take(limit) {
+		lobbies.push(serde_json::json!({
+			"id": reg.uuid,
+			"name": reg.metadata.name,
+			"reg_type": reg.reg_type,
+			"tags": reg.metadata.tags,
+			"app_version": reg.metadata.app_version,
+			"extra": reg.metadata.extra,
+			"counts": reg.connection_counts().await,
+			"limits": reg.limits
+		}));
+	}
+
+	Ok(warp::reply::json(&serde_json::json!({
+		"lobbies": lobbies,
+		"total": total,
+		"offset": offset,
+		"limit": limit
+	})))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::store::RegistrationRecord;
+	use futures_locks::RwLock;
+	use serde_json::Value;
+	use std::{collections::HashMap, sync::Arc};
+
+	fn registrations() -> Registrations {
+		let regs = [
+			("c", RegistrationType::Lobby, true, vec!["chess"]),
+			("a", RegistrationType::Lobby, true, vec!["chess", "ranked"]),
+			("b", RegistrationType::Lobby, false, vec!["chess"]),
+			("d", RegistrationType::HostClient, true, vec![]),
+			("e", RegistrationType::Broadcast, true, vec!["chess"]),
+		]
+		.into_iter()
+		.map(|(uuid, reg_type, public, tags)| {
+			let mut record = RegistrationRecord::minimal(uuid, reg_type);
+			record.public = public;
+			record.metadata.tags = tags.into_iter().map(str::to_owned).collect();
+
+			(uuid.to_owned(), Registration::from_record(record))
+		})
+		.collect::<HashMap<_, _>>();
+
+		Arc::new(RwLock::new(regs))
+	}
+
+	async fn list(tag: Option<&str>, offset: Option<usize>, limit: Option<usize>) -> Value {
+		let req = LobbiesRequest {
+			tag: tag.map(str::to_owned),
+			offset,
+			limit,
+		};
+		let reply = return_lobbies(req, None, registrations()).await.unwrap();
+		let body = warp::hyper::body::to_bytes(reply.into_response().into_body())
+			.await
+			.unwrap();
+
+		serde_json::from_slice(&body).unwrap()
+	}
+
+	fn ids(list: &Value) -> Vec<&str> {
+		list["lobbies"]
+			.as_array()
+			.unwrap()
+			.iter()
+			.map(|l| l["id"].as_str().unwrap())
+			.collect()
+	}
+
+	#[tokio::test]
+	async fn lists_only_public_joinable_registrations_in_order() {
+		let list = list(None, None, None).await;
+
+		assert_eq!(ids(&list), vec!["a", "c", "d"]);
+		assert_eq!(list["total"], 3);
+		assert_eq!(list["limit"], DEFAULT_LIMIT);
+	}
+
+	#[tokio::test]
+	async fn filters_by_tag() {
+		assert_eq!(ids(&list(Some("chess"), None, None).await), vec!["a", "c"]);
+		assert_eq!(ids(&list(Some("ranked"), None, None).await), vec!["a"]);
+	}
+
+	#[tokio::test]
+	async fn pages_through_them() {
+		let list = list(None, Some(1), Some(1)).await;
+
+		assert_eq!(ids(&list), vec!["c"]);
+		assert_eq!(list["total"], 3);
+		assert_eq!(list["offset"], 1);
+	}
+
+	#[tokio::test]
+	async fn limit_is_capped() {
+		assert_eq!(list(None, None, Some(1000)).await["limit"], MAX_LIMIT);
+	}
+}
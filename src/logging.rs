@@ -0,0 +1,47 @@
+use crate::config::Config;
+use std::str::FromStr;
+use tracing::level_filters::LevelFilter;
+
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub enum LogFormat {
+	/// Human-readable lines, for reading in a terminal
+	Text,
+	/// One JSON object per line, including every span that the event happened in, for log
+	/// aggregators
+	Json,
+}
+
+impl FromStr for LogFormat {
+	type Err = ();
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"text" => Ok(LogFormat::Text),
+			"json" => Ok(LogFormat::Json),
+			_ => Err(()),
+		}
+	}
+}
+
+/// Installs the global logger. `quiet` and `verbose` take precedence over `log_level`, since
+/// they're shorthands for turning logging off and up.
+pub fn init(conf: &Config) {
+	let level = if conf.quiet {
+		LevelFilter::OFF
+	} else if conf.verbose {
+		LevelFilter::DEBUG
+	} else {
+		conf.log_level
+	};
+
+	let builder = tracing_subscriber::fmt().with_max_level(level);
+
+	match conf.log_format {
+		LogFormat::Text => builder.init(),
+		LogFormat::Json => builder
+			.json()
+			.with_current_span(true)
+			.with_span_list(true)
+			.init(),
+	}
+}
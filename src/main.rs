@@ -1,22 +1,40 @@
 use clap::{App, Arg};
-use config::{Color, Config};
+use config::Config;
 use futures_locks::RwLock;
 use lazy_static::lazy_static;
+use listeners::Listener;
 use register::Registration;
 use sockets::*;
 use std::{collections::HashMap, convert::Infallible, process::exit, sync::Arc};
+use store::{FileStore, MemoryStore, RegistrationStore, StoreType, StoreWriter};
+use tracing::{error, info};
 use warp::Filter;
 
+mod admin;
 mod config;
 mod connections;
+mod errors;
+mod hashing;
+mod limits;
+mod listeners;
+mod lobbies;
+mod logging;
+mod metrics;
+mod queue;
 mod register;
+mod secret;
+mod shutdown;
 mod sockets;
 mod stats;
+mod store;
 
 type Registrations = Arc<RwLock<HashMap<String, Registration>>>;
 
 lazy_static! {
 	static ref CONFIG: Arc<RwLock<Config>> = Arc::new(RwLock::new(Config::default()));
+	static ref STORE: Arc<RwLock<StoreWriter>> = Arc::new(RwLock::new(StoreWriter::spawn(
+		Box::new(MemoryStore::default())
+	)));
 }
 
 #[tokio::main]
@@ -24,11 +42,20 @@ async fn main() {
 	let matches = App::new("warp_router")
 		.version("1.0")
 		.about("Simple server-side websocket router")
+		.arg(Arg::with_name("config")
+			.short("c")
+			.long("config")
+			.help("A TOML file to read options from; flags and environment variables override it")
+			.takes_value(true))
 		.arg(Arg::with_name("port")
 			.short("p")
 			.long("port")
 			.help("The port to run the router on")
 			.takes_value(true))
+		.arg(Arg::with_name("listen")
+			.long("listen")
+			.help("Comma-separated addresses to listen on, e.g. 'tls://[::]:443,127.0.0.1:8080,unix:/run/ws_router.sock'; overrides --port and --secure")
+			.takes_value(true))
 		.arg(Arg::with_name("quiet")
 			.short("q")
 			.long("quiet")
@@ -43,6 +70,16 @@ async fn main() {
 			.long("verbose")
 			.help("Enables verbose logging")
 			.conflicts_with("quiet"))
+		.arg(Arg::with_name("log_level")
+			.long("log_level")
+			.help("The most detailed level of logs to show; overridden by --quiet and --verbose")
+			.possible_values(&["off", "error", "warn", "info", "debug", "trace"])
+			.takes_value(true))
+		.arg(Arg::with_name("log_format")
+			.long("log_format")
+			.help("Whether to log human-readable text or one JSON object per line")
+			.possible_values(&["text", "json"])
+			.takes_value(true))
 		.arg(Arg::with_name("key_file")
 			.long("key_file")
 			.help("The key file, if you are running the server with TLS")
@@ -51,7 +88,11 @@ async fn main() {
 			.long("cert_file")
 			.help("The certificate, if you are running the server with TLS")
 			.takes_value(true))
-		.arg(Arg::with_name("remove")
+		.arg(Arg::with_name("secret_key")
+			.long("secret_key")
+			.help("The salt to hash registration keys with, at least 8 bytes long; random on every start by default")
+			.takes_value(true))
+		.arg(Arg::with_name("auto_remove")
 			.short("r")
 			.long("auto_remove")
 			.help("Automatically remove registrations when they have no devices connected to them anymore")
@@ -61,103 +102,453 @@ async fn main() {
 			.long("reject")
 			.help("Automatically reject registrations when the requested ID is already in use or invalid")
 			.takes_value(false))
+		.arg(Arg::with_name("store")
+			.long("store")
+			.help("Where to keep registrations so they survive restarts; either 'memory' or 'file'")
+			.possible_values(&["memory", "file"])
+			.takes_value(true))
+		.arg(Arg::with_name("store_path")
+			.long("store_path")
+			.help("The file to keep registrations in, if running with '--store file'")
+			.takes_value(true))
+		.arg(Arg::with_name("default_ttl")
+			.long("default_ttl")
+			.help("How many seconds registrations live for when they don't request a ttl")
+			.takes_value(true))
+		.arg(Arg::with_name("max_ttl")
+			.long("max_ttl")
+			.help("The longest ttl, in seconds, that a registration may have")
+			.takes_value(true))
+		.arg(Arg::with_name("idle_ttl")
+			.long("idle_ttl")
+			.help("Remove registrations that have had no connections for this many seconds")
+			.takes_value(true))
+		.arg(Arg::with_name("unused_ttl")
+			.long("unused_ttl")
+			.help("Remove registrations that nobody has ever connected to after this many seconds, unless idle_ttl is set; 0 to keep them")
+			.takes_value(true))
+		.arg(Arg::with_name("reap_interval")
+			.long("reap_interval")
+			.help("How often, in seconds, to check for expired registrations")
+			.takes_value(true))
+		.arg(Arg::with_name("resume_grace")
+			.long("resume_grace")
+			.help("How many seconds a dropped connection has to resume its session")
+			.takes_value(true))
+		.arg(Arg::with_name("resume_buffer_len")
+			.long("resume_buffer_len")
+			.help("The most messages to buffer for a dropped connection while it resumes")
+			.takes_value(true))
+		.arg(Arg::with_name("resume_buffer_bytes")
+			.long("resume_buffer_bytes")
+			.help("The most bytes to buffer for a dropped connection while it resumes")
+			.takes_value(true))
+		.arg(Arg::with_name("queue_len")
+			.long("queue_len")
+			.help("The most messages that can be waiting to be sent to a single connection")
+			.takes_value(true))
+		.arg(Arg::with_name("queue_policy")
+			.long("queue_policy")
+			.help("What to do when a connection's queue is full")
+			.possible_values(&["drop_oldest", "drop_newest", "disconnect"])
+			.takes_value(true))
+		.arg(Arg::with_name("argon2_memory")
+			.long("argon2_memory")
+			.help("How much memory, in KiB, hashing each key should take")
+			.takes_value(true))
+		.arg(Arg::with_name("argon2_iterations")
+			.long("argon2_iterations")
+			.help("How many passes to make over the memory when hashing each key")
+			.takes_value(true))
+		.arg(Arg::with_name("argon2_variant")
+			.long("argon2_variant")
+			.help("Which variant of argon2 to hash keys with")
+			.possible_values(&["argon2d", "argon2i", "argon2id"])
+			.takes_value(true))
+		.arg(Arg::with_name("hash_concurrency")
+			.long("hash_concurrency")
+			.help("The most keys that can be hashed or verified at once; defaults to the number of CPUs")
+			.takes_value(true))
+		.arg(Arg::with_name("verify_cache_ttl")
+			.long("verify_cache_ttl")
+			.help("How many seconds to remember a key that was verified, so that reconnecting doesn't hash it again; 0 to disable")
+			.takes_value(true))
+		.arg(Arg::with_name("ip_rate_limit")
+			.long("ip_rate_limit")
+			.help("How many requests per second each IP address may make, on average; 0 (the default) to disable")
+			.takes_value(true))
+		.arg(Arg::with_name("ip_rate_burst")
+			.long("ip_rate_burst")
+			.help("How many requests each IP address may make at once")
+			.takes_value(true))
+		.arg(Arg::with_name("id_rate_limit")
+			.long("id_rate_limit")
+			.help("How many requests per second may be made for each registration, on average; 0 (the default) to disable")
+			.takes_value(true))
+		.arg(Arg::with_name("id_rate_burst")
+			.long("id_rate_burst")
+			.help("How many requests may be made for each registration at once")
+			.takes_value(true))
+		.arg(Arg::with_name("max_failed_attempts")
+			.long("max_failed_attempts")
+			.help("Lock a registration out after this many wrong keys in a row; 0 to disable")
+			.takes_value(true))
+		.arg(Arg::with_name("lockout_duration")
+			.long("lockout_duration")
+			.help("How many seconds a registration stays locked out for")
+			.takes_value(true))
+		.arg(Arg::with_name("trusted_proxies")
+			.long("trusted_proxies")
+			.help("Comma-separated IP addresses of reverse proxies (or 'unix' for Unix sockets) whose X-Forwarded-For header is trusted")
+			.takes_value(true))
+		.arg(Arg::with_name("max_metadata_bytes")
+			.long("max_metadata_bytes")
+			.help("The most bytes of JSON metadata that a registration may have")
+			.takes_value(true))
+		.arg(Arg::with_name("drain_timeout")
+			.long("drain_timeout")
+			.help("How many seconds to wait for connections to close when shutting down")
+			.takes_value(true))
+		.arg(Arg::with_name("shutdown_reason")
+			.long("shutdown_reason")
+			.help("The reason to send along with the close frame when shutting down")
+			.takes_value(true))
+		.arg(Arg::with_name("shutdown_snapshot")
+			.long("shutdown_snapshot")
+			.help("A file to save every registration to when shutting down")
+			.takes_value(true))
+		.arg(Arg::with_name("ping_interval")
+			.long("ping_interval")
+			.help("How many seconds to wait between pinging each connection")
+			.takes_value(true))
+		.arg(Arg::with_name("pong_timeout")
+			.long("pong_timeout")
+			.help("How many seconds a connection has to answer a ping before it counts as missed")
+			.takes_value(true))
+		.arg(Arg::with_name("max_missed_pongs")
+			.long("max_missed_pongs")
+			.help("Disconnect connections that miss this many pongs in a row; 0 to disable")
+			.takes_value(true))
+		.arg(Arg::with_name("admin_token")
+			.long("admin_token")
+			.help("The bearer token for the /admin API, which is disabled if this isn't set")
+			.takes_value(true))
+		.arg(Arg::with_name("stats")
+			.long("stats")
+			.help("Who may see /stats: only the admin, anyone (but only aggregate counts), or nobody")
+			.possible_values(&["admin", "aggregate", "disabled"])
+			.takes_value(true))
+		.arg(Arg::with_name("stats_interval")
+			.long("stats_interval")
+			.help("How many seconds to wait between sampling the system stats shown in /stats")
+			.takes_value(true))
+		.arg(Arg::with_name("max_message_size")
+			.long("max_message_size")
+			.help("The most bytes that a single message sent over a websocket may have")
+			.takes_value(true))
+		.arg(Arg::with_name("max_frame_size")
+			.long("max_frame_size")
+			.help("The most bytes that a single websocket frame may have")
+			.takes_value(true))
+		.arg(Arg::with_name("message_rate_limit")
+			.long("message_rate_limit")
+			.help("How many messages per second each connection may send after its burst; 0 to disable")
+			.takes_value(true))
+		.arg(Arg::with_name("message_rate_burst")
+			.long("message_rate_burst")
+			.help("How many messages each connection may send at once")
+			.takes_value(true))
+		.arg(Arg::with_name("byte_rate_limit")
+			.long("byte_rate_limit")
+			.help("How many bytes per second each connection may send after its burst; 0 to disable")
+			.takes_value(true))
+		.arg(Arg::with_name("byte_rate_burst")
+			.long("byte_rate_burst")
+			.help("How many bytes each connection may send at once")
+			.takes_value(true))
+		.arg(Arg::with_name("total_message_rate_limit")
+			.long("total_message_rate_limit")
+			.help("How many messages per second all of a registration's connections may send together after their burst; 0 to disable")
+			.takes_value(true))
+		.arg(Arg::with_name("total_byte_rate_limit")
+			.long("total_byte_rate_limit")
+			.help("How many bytes per second all of a registration's connections may send together after their burst; 0 to disable")
+			.takes_value(true))
+		.arg(Arg::with_name("rate_limit_policy")
+			.long("rate_limit_policy")
+			.help("What to do with messages that a connection sends faster than its rate limits")
+			.possible_values(&["drop", "delay", "disconnect"])
+			.takes_value(true))
 		.get_matches();
 
-	let mut conf = CONFIG.write().await;
-	if !conf.parse_args(matches) {
-		exit(1);
+	match Config::load(&matches) {
+		Ok(conf) => {
+			logging::init(&conf);
+			hashing::init(conf.hash_concurrency);
+			*CONFIG.write().await = conf;
+		}
+		Err(err) => {
+			// the logger can't be set up without a valid config, so this has to go straight
+			// to stderr
+			eprintln!("Invalid configuration: {}", err);
+			exit(1);
+		}
 	}
-	drop(conf);
 
-	let registrations: Registrations = Arc::new(RwLock::new(HashMap::new()));
+	let registrations: Registrations = Arc::new(RwLock::new(load_registrations().await));
+
+	register::spawn_reaper(registrations.clone());
+	stats::spawn_sampler();
 
 	let cors = warp::cors()
-		.allow_method(warp::hyper::Method::GET)
-		.allow_header(warp::hyper::header::CONTENT_TYPE)
+		.allow_methods([
+			warp::hyper::Method::GET,
+			warp::hyper::Method::POST,
+			warp::hyper::Method::DELETE,
+		])
+		.allow_headers([
+			warp::hyper::header::CONTENT_TYPE,
+			warp::hyper::header::AUTHORIZATION,
+		])
+		.expose_header(warp::hyper::header::RETRY_AFTER)
 		.allow_any_origin()
 		.build();
 
 	let register_route = warp::path("register")
 		.and(warp::get())
+		.and(shutdown::accepting())
 		.and(warp::query())
+		.and(limits::client_addr())
 		.and(with_registrations(registrations.clone()))
-		.and_then(Registration::new_handler)
-		.with(&cors);
+		.and_then(Registration::new_handler);
+
+	// leave plenty of room for everything besides the metadata, which is limited on its own
+	let body_limit = CONFIG.read().await.max_metadata_bytes as u64 + 4096;
+
+	let register_json_route = warp::path("register")
+		.and(warp::post())
+		.and(shutdown::accepting())
+		.and(warp::body::content_length_limit(body_limit))
+		.and(warp::body::json())
+		.and(limits::client_addr())
+		.and(with_registrations(registrations.clone()))
+		.and_then(Registration::new_json_handler);
+
+	let lookup_route = warp::path!("registrations" / String)
+		.and(warp::get())
+		.and(warp::header::optional("authorization"))
+		.and(limits::client_addr())
+		.and(with_registrations(registrations.clone()))
+		.and_then(Registration::lookup_handler);
+
+	let rotate_route = warp::path!("registrations" / String / "keys")
+		.and(warp::post())
+		.and(warp::header::optional("authorization"))
+		.and(warp::body::content_length_limit(4096))
+		.and(warp::body::json())
+		.and(limits::client_addr())
+		.and(with_registrations(registrations.clone()))
+		.and_then(Registration::rotate_handler);
 
 	let connect_route = warp::path("connect")
+		.and(shutdown::accepting())
 		.and(warp::ws())
 		.and(warp::query())
+		.and(limits::client_addr())
 		.and(with_registrations(registrations.clone()))
-		.and_then(Socket::connect_handler)
-		.with(&cors);
+		.and_then(Socket::connect_handler);
 
 	let remove_route = warp::path("remove")
 		.and(warp::get())
 		.and(warp::query())
+		.and(limits::client_addr())
+		.and(with_registrations(registrations.clone()))
+		.and_then(Registration::remove_handler);
+
+	let admin_list_route = warp::path!("registrations")
+		.and(warp::get())
+		.and(with_registrations(registrations.clone()))
+		.and_then(admin::list_registrations);
+
+	let admin_get_route = warp::path!("registrations" / String)
+		.and(warp::get())
+		.and(with_registrations(registrations.clone()))
+		.and_then(admin::get_registration);
+
+	let admin_remove_route = warp::path!("registrations" / String)
+		.and(warp::delete())
+		.and(limits::client_addr())
+		.and(with_registrations(registrations.clone()))
+		.and_then(admin::remove_registration);
+
+	let admin_kick_route = warp::path!("registrations" / String / "connections" / String)
+		.and(warp::delete())
+		.and(limits::client_addr())
+		.and(with_registrations(registrations.clone()))
+		.and_then(admin::kick_connection);
+
+	let admin_notice_route = warp::path!("registrations" / String / "notice")
+		.and(warp::post())
+		.and(warp::body::content_length_limit(4096))
+		.and(warp::body::json())
+		.and(limits::client_addr())
+		.and(with_registrations(registrations.clone()))
+		.and_then(admin::send_notice);
+
+	// every admin route is checked once up front, so that a request isn't rate limited or
+	// logged again for every route that it falls through before finding its own
+	let admin_route = warp::path("admin").and(admin::authorized()).and(
+		admin_list_route
+			.or(admin_get_route)
+			.or(admin_remove_route)
+			.or(admin_kick_route)
+			.or(admin_notice_route),
+	);
+
+	let lobbies_route = warp::path("lobbies")
+		.and(warp::get())
+		.and(warp::query())
+		.and(limits::client_addr())
 		.and(with_registrations(registrations.clone()))
-		.and_then(Registration::remove_handler)
-		.with(&cors);
+		.and_then(lobbies::return_lobbies);
 
 	let stats_route = warp::path("stats")
 		.and(warp::get())
+		.and(warp::header::optional("authorization"))
+		.and(limits::client_addr())
 		.and(with_registrations(registrations.clone()))
-		.and_then(stats::return_stats)
-		.with(cors);
+		.and_then(stats::return_stats);
+
+	let metrics_route = warp::path("metrics")
+		.and(warp::get())
+		.and(with_registrations(registrations.clone()))
+		.and_then(metrics::return_metrics);
 
 	let routes = register_route
+		.or(register_json_route)
+		.or(lookup_route)
+		.or(rotate_route)
 		.or(connect_route)
 		.or(remove_route)
-		.or(stats_route);
+		.or(lobbies_route)
+		.or(admin_route)
+		.or(stats_route)
+		.or(metrics_route)
+		.recover(errors::recover)
+		// after recovering, so that browsers can read the errors too
+		.with(cors);
 
 	let conf = CONFIG.read().await;
-	let port = conf.port;
-
-	let log_str = std::net::UdpSocket::bind(format!("0.0.0.0:{}", port))
-		.ok()
-		.and_then(|sock|
-			sock.connect("8.8.8.8:80")
-				.ok()
-				.and_then(|_|
-					sock.local_addr()
-						.ok()
-						.map(|addr| format!(" at \x1b[1m{}:{}\x1b[0m", addr.ip(), port))
-				),
-		)
-		.unwrap_or_default();
-
-	if conf.secure {
-		log!(
-			!conf.quiet,
-			Color::Blue,
-			"Running server{} with TLS",
-			log_str
-		);
-
-		let key_path = conf
-			.key_file
-			.as_ref()
-			.expect("Please provide a key file")
-			.to_owned();
-
-		let cert_path = conf
-			.cert_file
-			.as_ref()
-			.expect("Please provide a cert file")
-			.to_owned();
-
-		drop(conf);
-		warp::serve(routes)
-			.tls()
-			.cert_path(cert_path)
-			.key_path(key_path)
-			.run(([0, 0, 0, 0], port))
-			.await
-	} else {
-		log!(!conf.quiet, Color::Blue, "Running server{}...", log_str);
-
-		drop(conf);
-		warp::serve(routes).run(([0, 0, 0, 0], port)).await;
+	let listeners = conf.listeners();
+	let key_file = conf.key_file.clone();
+	let cert_file = conf.cert_file.clone();
+	drop(conf);
+
+	let mut servers = Vec::new();
+
+	for listener in listeners.iter().cloned() {
+		let routes = routes.clone();
+
+		match listener {
+			Listener::Tcp(addr) => {
+				match warp::serve(routes)
+					.try_bind_with_graceful_shutdown(addr, shutdown::triggered())
+				{
+					Ok((addr, server)) => {
+						info!("Listening on {}", Listener::Tcp(addr));
+						servers.push(tokio::spawn(server));
+					}
+					Err(err) => {
+						error!("Failed to listen on {}: {}", listener, err);
+						exit(1);
+					}
+				}
+			}
+			Listener::Tls(addr) => {
+				// the config wouldn't have validated if there were tls listeners without these
+				let (key_file, cert_file) = match (&key_file, &cert_file) {
+					(Some(key), Some(cert)) => (key, cert),
+					_ => {
+						error!("Failed to listen on {}: no key_file or cert_file", listener);
+						exit(1);
+					}
+				};
+
+				if let Err(err) = listeners::check_tls_files(key_file, cert_file) {
+					error!("Failed to listen on {}: {}", listener, err);
+					exit(1);
+				}
+
+				// warp's tls server has no fallible way to bind, and panics instead if the address
+				// is in use, so that's checked first to fail the same way as every other listener
+				if let Err(err) = listeners::check_addr(addr) {
+					error!("Failed to listen on {}: {}", listener, err);
+					exit(1);
+				}
+
+				let (addr, server) = warp::serve(routes)
+					.tls()
+					.cert_path(cert_file)
+					.key_path(key_file)
+					.bind_with_graceful_shutdown(addr, shutdown::triggered());
+
+				info!("Listening on {}", Listener::Tls(addr));
+				servers.push(tokio::spawn(server));
+			}
+			Listener::Unix(ref path) => match listeners::bind_unix(path) {
+				Ok(incoming) => {
+					info!("Listening on {}", listener);
+					servers.push(tokio::spawn(
+						warp::serve(routes)
+							.serve_incoming_with_graceful_shutdown(incoming, shutdown::triggered()),
+					));
+				}
+				Err(err) => {
+					error!("Failed to listen on {}: {}", listener, err);
+					exit(1);
+				}
+			},
+		}
 	}
+
+	tokio::spawn(shutdown::listen_for_signal());
+
+	// every listener stops at the same time, once the server is told to shut down
+	futures_util::future::join_all(servers).await;
+	listeners::cleanup(&listeners);
+
+	shutdown::drain(registrations).await;
+	info!("Shut down");
+}
+
+async fn load_registrations() -> HashMap<String, Registration> {
+	let conf = CONFIG.read().await;
+
+	let store: Box<dyn RegistrationStore> = match conf.store {
+		StoreType::Memory => Box::new(MemoryStore::default()),
+		StoreType::File => match FileStore::open(&conf.store_path) {
+			Ok(store) => Box::new(store),
+			Err(err) => {
+				error!("Failed to open store at '{}': {}", conf.store_path, err);
+				exit(1);
+			}
+		},
+	};
+	drop(conf);
+
+	let records = store.load().unwrap_or_else(|err| {
+		error!("Failed to load registrations from store: {}", err);
+		exit(1);
+	});
+
+	info!("Loaded {} registration(s) from the store", records.len());
+
+	*STORE.write().await = StoreWriter::spawn(store);
+
+	records
+		.into_iter()
+		.map(|r| (r.uuid.to_owned(), Registration::from_record(r)))
+		.collect()
 }
 
 fn with_registrations(
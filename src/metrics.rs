@@ -0,0 +1,225 @@
+use crate::Registrations;
+use lazy_static::lazy_static;
+use prometheus::{
+	core::Collector, register_int_counter, register_int_counter_vec, register_int_gauge_vec,
+	Encoder, IntCounter, IntCounterVec, IntGaugeVec, TextEncoder,
+};
+use std::{collections::HashMap, fmt::Debug};
+use warp::{
+	reject::{self, Reject},
+	Rejection, Reply,
+};
+
+// registering can only fail if the name or labels are invalid or already registered, both of
+// which would be a bug in the list below
+lazy_static! {
+	pub static ref REGISTRATIONS_CREATED: IntCounter = register_int_counter!(
+		"ws_router_registrations_created_total",
+		"Registrations that have been created"
+	)
+	.expect("Failed to register metric");
+	pub static ref REGISTRATIONS_REMOVED: IntCounterVec = register_int_counter_vec!(
+		"ws_router_registrations_removed_total",
+		"Registrations that have been removed, by why they were removed",
+		&["reason"]
+	)
+	.expect("Failed to register metric");
+	pub static ref REGISTRATIONS: IntGaugeVec = register_int_gauge_vec!(
+		"ws_router_registrations",
+		"Registrations that currently exist",
+		&["reg_type"]
+	)
+	.expect("Failed to register metric");
+	pub static ref CONNECTIONS: IntGaugeVec = register_int_gauge_vec!(
+		"ws_router_connections",
+		"Connections that are currently open",
+		&["reg_type", "sock_type"]
+	)
+	.expect("Failed to register metric");
+	pub static ref MESSAGES_FORWARDED: IntCounter = register_int_counter!(
+		"ws_router_messages_forwarded_total",
+		"Messages that have been queued to be sent to a connection"
+	)
+	.expect("Failed to register metric");
+	pub static ref BYTES_FORWARDED: IntCounter = register_int_counter!(
+		"ws_router_bytes_forwarded_total",
+		"Payload bytes that have been queued to be sent to a connection"
+	)
+	.expect("Failed to register metric");
+	pub static ref REJECTIONS: IntCounterVec = register_int_counter_vec!(
+		"ws_router_rejections_total",
+		"Requests that have been rejected, by why they were rejected",
+		&["rejection"]
+	)
+	.expect("Failed to register metric");
+	pub static ref SEND_FAILURES: IntCounter = register_int_counter!(
+		"ws_router_send_failures_total",
+		"Times that writing a message to a websocket failed"
+	)
+	.expect("Failed to register metric");
+	pub static ref PING_TIMEOUTS: IntCounter = register_int_counter!(
+		"ws_router_ping_timeouts_total",
+		"Pings that weren't answered with a pong before the pong timeout"
+	)
+	.expect("Failed to register metric");
+	pub static ref DEAD_PEER_DISCONNECTS: IntCounter = register_int_counter!(
+		"ws_router_dead_peer_disconnects_total",
+		"Connections that were disconnected for missing too many pongs in a row"
+	)
+	.expect("Failed to register metric");
+	pub static ref DROPPED_MESSAGES: IntCounter = register_int_counter!(
+		"ws_router_dropped_messages_total",
+		"Messages that were dropped because a connection's queue was full"
+	)
+	.expect("Failed to register metric");
+	pub static ref MESSAGE_LIMIT_DISCONNECTS: IntCounterVec = register_int_counter_vec!(
+		"ws_router_message_limit_disconnects_total",
+		"Connections that were disconnected for sending a message that their registration doesn't allow, by why",
+		&["reason"]
+	)
+	.expect("Failed to register metric");
+	pub static ref RATE_LIMITED_MESSAGES: IntCounterVec = register_int_counter_vec!(
+		"ws_router_rate_limited_messages_total",
+		"Messages that were sent faster than the connection's rate limits, by what was done about it",
+		&["action"]
+	)
+	.expect("Failed to register metric");
+	pub static ref SLOW_DISCONNECTS: IntCounter = register_int_counter!(
+		"ws_router_slow_disconnects_total",
+		"Connections that were disconnected because their queue was full"
+	)
+	.expect("Failed to register metric");
+}
+
+/// Counts the rejection under the name of its variant, and then turns it into a `Rejection`
+pub fn reject<R: Reject + Debug>(rejection: R) -> Rejection {
+	// leave out any fields, so that every value of them doesn't get its own label
+	let name = format!("{:?}", rejection);
+	let name = name.split([' ', '(']).next().unwrap_or_default();
+
+	REJECTIONS.with_label_values(&[name]).inc();
+	reject::custom(rejection)
+}
+
+pub fn not_found() -> Rejection {
+	REJECTIONS.with_label_values(&["NotFound"]).inc();
+	reject::not_found()
+}
+
+pub async fn return_metrics(rgs: Registrations) -> Result<impl Reply, Rejection> {
+	// these are gauges of what exists right now, so they're easier to just count from scratch
+	// than to keep track of every time something is added or removed
+	let mut registrations = HashMap::new();
+	let mut connections = HashMap::new();
+
+	let regs = rgs.read().await;
+
+	for reg in regs.values() {
+		let reg_type = format!("{:?}", reg.reg_type).to_lowercase();
+
+		for con in reg.connections.read().await.iter() {
+			let sock_type = format!("{:?}", con.sock_type).to_lowercase();
+
+			*connections
+				.entry(vec![reg_type.clone(), sock_type])
+				.or_insert(0) += 1;
+		}
+
+		*registrations.entry(vec![reg_type]).or_insert(0) += 1;
+	}
+
+	drop(regs);
+
+	set_counts(&REGISTRATIONS, &["reg_type"], &registrations);
+	set_counts(&CONNECTIONS, &["reg_type", "sock_type"], &connections);
+
+	let encoder = TextEncoder::new();
+	let mut buffer = Vec::new();
+
+	if encoder.encode(&prometheus::gather(), &mut buffer).is_err() {
+		return Err(reject::reject());
+	}
+
+	Ok(warp::reply::with_header(
+		String::from_utf8_lossy(&buffer).into_owned(),
+		"content-type",
+		encoder.format_type(),
+	))
+}
+
+/// Sets each of `gauge`'s values to its count in `counts`, which is keyed by the values of
+/// `labels`. Values that aren't counted any more are set to 0 instead of being reset, since a
+/// scrape that happens at the same time as this would see them missing in between.
+fn set_counts(gauge: &IntGaugeVec, labels: &[&str], counts: &HashMap<Vec<String>, i64>) {
+	for family in gauge.collect() {
+		for metric in family.get_metric() {
+			let values: Vec<String> = labels
+				.iter()
+				.map(|name| {
+					metric
+						.get_label()
+						.iter()
+						.find(|l| l.get_name() == *name)
+						.map(|l| l.get_value().to_owned())
+						.unwrap_or_default()
+				})
+				.collect();
+
+			if !counts.contains_key(&values) {
+				gauge.with_label_values(&as_strs(&values)).set(0);
+			}
+		}
+	}
+
+	for (values, count) in counts {
+		gauge.with_label_values(&as_strs(values)).set(*count);
+	}
+}
+
+fn as_strs(values: &[String]) -> Vec<&str> {
+	values.iter().map(String::as_str).collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use prometheus::Opts;
+
+	fn counts(list: &[(&[&str], i64)]) -> HashMap<Vec<String>, i64> {
+		list.iter()
+			.map(|(labels, count)| (labels.iter().map(|l| l.to_string()).collect(), *count))
+			.collect()
+	}
+
+	#[test]
+	fn set_counts_zeroes_what_is_gone() {
+		let labels = ["reg_type", "sock_type"];
+		let gauge = IntGaugeVec::new(Opts::new("test", "test"), &labels).unwrap();
+
+		set_counts(
+			&gauge,
+			&labels,
+			&counts(&[(&["lobby", "socket"], 3), (&["hostclient", "host"], 1)]),
+		);
+		assert_eq!(gauge.with_label_values(&["lobby", "socket"]).get(), 3);
+		assert_eq!(gauge.with_label_values(&["hostclient", "host"]).get(), 1);
+
+		set_counts(&gauge, &labels, &counts(&[(&["lobby", "socket"], 2)]));
+		assert_eq!(gauge.with_label_values(&["lobby", "socket"]).get(), 2);
+		assert_eq!(gauge.with_label_values(&["hostclient", "host"]).get(), 0);
+	}
+
+	#[test]
+	fn reject_counts_by_variant_name() {
+		#[derive(Debug)]
+		enum Test {
+			Field(#[allow(dead_code)] u64),
+		}
+
+		impl Reject for Test {}
+
+		let before = REJECTIONS.with_label_values(&["Field"]).get();
+		reject(Test::Field(5));
+		assert_eq!(REJECTIONS.with_label_values(&["Field"]).get(), before + 1);
+	}
+}
@@ -0,0 +1,259 @@
+use crate::{metrics, sockets::close_codes};
+use std::{
+	collections::VecDeque,
+	str::FromStr,
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		Arc, Mutex, MutexGuard,
+	},
+};
+use tokio::sync::Notify;
+use warp::ws::Message;
+
+/// What to do with a message when the connection it's being sent to already has a full queue
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub enum QueuePolicy {
+	/// Drop the oldest queued message to make room for the new one
+	DropOldest,
+	/// Drop the new message
+	DropNewest,
+	/// Drop everything that's queued and close the connection
+	Disconnect,
+}
+
+impl FromStr for QueuePolicy {
+	type Err = ();
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"drop_oldest" => Ok(QueuePolicy::DropOldest),
+			"drop_newest" => Ok(QueuePolicy::DropNewest),
+			"disconnect" => Ok(QueuePolicy::Disconnect),
+			_ => Err(()),
+		}
+	}
+}
+
+/// A bounded queue of messages waiting to be sent to a single connection. Senders only ever
+/// push onto it, which never waits, and the connection's writer task is the only thing that
+/// pops from it, so one slow connection can never hold up anyone else. Messages are behind an
+/// `Arc`, so a message that's sent to many connections is only ever held in memory once.
+pub struct OutboundQueue {
+	state: Mutex<QueueState>,
+	notify: Notify,
+	capacity: usize,
+	policy: QueuePolicy,
+	pub sent: AtomicU64,
+	pub sent_bytes: AtomicU64,
+	pub dropped: AtomicU64,
+}
+
+struct QueueState {
+	msgs: VecDeque<Arc<Message>>,
+	closed: bool,
+	/// whether the router closed the connection on purpose, by sending it a close frame
+	closed_by_server: bool,
+}
+
+impl OutboundQueue {
+	pub fn new(capacity: usize, policy: QueuePolicy) -> OutboundQueue {
+		OutboundQueue {
+			state: Mutex::new(QueueState {
+				msgs: VecDeque::new(),
+				closed: false,
+				closed_by_server: false,
+			}),
+			notify: Notify::new(),
+			capacity,
+			policy,
+			sent: AtomicU64::new(0),
+			sent_bytes: AtomicU64::new(0),
+			dropped: AtomicU64::new(0),
+		}
+	}
+
+	// a panic while holding this lock can't leave the queue in an invalid state, so there's no
+	// reason to stop using it if that happens
+	fn state(&self) -> MutexGuard<'_, QueueState> {
+		self.state.lock().unwrap_or_else(|p| p.into_inner())
+	}
+
+	/// Queues a message to be sent. Returns false if the message won't be sent, because the
+	/// queue was closed or full.
+	pub fn push(&self, msg: Message) -> bool {
+		self.push_shared(Arc::new(msg))
+	}
+
+	/// Queues a message that may also be queued for other connections
+	pub fn push_shared(&self, msg: Arc<Message>) -> bool {
+		let mut state = self.state();
+
+		if state.closed {
+			return false;
+		}
+
+		if state.msgs.len() >= self.capacity {
+			match self.policy {
+				QueuePolicy::DropOldest => {
+					state.msgs.pop_front();
+					self.record_drop(1);
+				}
+				QueuePolicy::DropNewest => {
+					self.record_drop(1);
+					return false;
+				}
+				QueuePolicy::Disconnect => {
+					self.record_drop(state.msgs.len() as u64 + 1);
+					metrics::SLOW_DISCONNECTS.inc();
+
+					// there's no point in making them wait for everything that's already
+					// queued if they're getting disconnected anyways
+					state.msgs.clear();
+					state.msgs.push_back(Arc::new(Message::close_with(
+						close_codes::SLOW_CONSUMER,
+						"Connection is too slow to keep up with messages",
+					)));
+					state.closed = true;
+					state.closed_by_server = true;
+
+					drop(state);
+					self.notify.notify_one();
+					return false;
+				}
+			}
+		}
+
+		state.msgs.push_back(msg);
+		drop(state);
+
+		self.notify.notify_one();
+		true
+	}
+
+	/// Stops accepting new messages. Everything that's already queued is still sent, followed
+	/// by `last` if there is one, and then the writer task closes the websocket.
+	pub fn close(&self, last: Option<Message>) {
+		let mut state = self.state();
+
+		if state.closed {
+			return;
+		}
+
+		if let Some(msg) = last {
+			state.msgs.push_back(Arc::new(msg));
+			state.closed_by_server = true;
+		}
+
+		state.closed = true;
+		drop(state);
+
+		self.notify.notify_one();
+	}
+
+	/// Waits for the next message to send. Returns `None` once the queue is closed and empty.
+	pub async fn pop(&self) -> Option<Arc<Message>> {
+		loop {
+			let notified = self.notify.notified();
+
+			{
+				let mut state = self.state();
+
+				if let Some(msg) = state.msgs.pop_front() {
+					return Some(msg);
+				}
+
+				if state.closed {
+					return None;
+				}
+			}
+
+			notified.await;
+		}
+	}
+
+	/// Whether the router sent this connection a close frame, as opposed to the queue only
+	/// being closed because the websocket went away
+	pub fn closed_by_server(&self) -> bool {
+		self.state().closed_by_server
+	}
+
+	pub fn len(&self) -> usize {
+		self.state().msgs.len()
+	}
+
+	fn record_drop(&self, count: u64) {
+		self.dropped.fetch_add(count, Ordering::Relaxed);
+		metrics::DROPPED_MESSAGES.inc_by(count);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn text(queue: &OutboundQueue) -> Vec<String> {
+		queue
+			.state()
+			.msgs
+			.iter()
+			.map(|m| m.to_str().unwrap_or_default().to_owned())
+			.collect()
+	}
+
+	fn full_queue(policy: QueuePolicy) -> OutboundQueue {
+		let queue = OutboundQueue::new(2, policy);
+		assert!(queue.push(Message::text("a")));
+		assert!(queue.push(Message::text("b")));
+		queue
+	}
+
+	#[test]
+	fn drop_oldest_makes_room() {
+		let queue = full_queue(QueuePolicy::DropOldest);
+
+		assert!(queue.push(Message::text("c")));
+		assert_eq!(text(&queue), vec!["b", "c"]);
+		assert_eq!(queue.dropped.load(Ordering::Relaxed), 1);
+	}
+
+	#[test]
+	fn drop_newest_keeps_what_is_queued() {
+		let queue = full_queue(QueuePolicy::DropNewest);
+
+		assert!(!queue.push(Message::text("c")));
+		assert_eq!(text(&queue), vec!["a", "b"]);
+		assert_eq!(queue.dropped.load(Ordering::Relaxed), 1);
+	}
+
+	#[test]
+	fn disconnect_replaces_everything_with_a_close() {
+		let queue = full_queue(QueuePolicy::Disconnect);
+
+		assert!(!queue.push(Message::text("c")));
+		assert_eq!(queue.dropped.load(Ordering::Relaxed), 3);
+		assert_eq!(queue.len(), 1);
+		assert!(queue.closed_by_server());
+		assert!(!queue.push(Message::text("d")));
+	}
+
+	#[tokio::test]
+	async fn close_sends_what_is_queued_first() {
+		let queue = OutboundQueue::new(4, QueuePolicy::DropNewest);
+		queue.push(Message::text("a"));
+		queue.close(Some(Message::close()));
+
+		assert!(!queue.push(Message::text("b")));
+		assert!(queue.pop().await.unwrap().is_text());
+		assert!(queue.pop().await.unwrap().is_close());
+		assert!(queue.pop().await.is_none());
+		assert!(queue.closed_by_server());
+	}
+
+	#[test]
+	fn policy_parses() {
+		assert_eq!("drop_oldest".parse(), Ok(QueuePolicy::DropOldest));
+		assert_eq!("drop_newest".parse(), Ok(QueuePolicy::DropNewest));
+		assert_eq!("disconnect".parse(), Ok(QueuePolicy::Disconnect));
+		assert!("block".parse::<QueuePolicy>().is_err());
+	}
+}
@@ -0,0 +1,99 @@
+use crate::sockets::SocketType;
+use serde::{Deserialize, Serialize};
+
+/// The most connections that a registration will accept, in total and of each `SocketType`.
+/// `None` means there's no limit.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+pub struct ConnectionLimits {
+	pub max_connections: Option<usize>,
+	pub max_hosts: Option<usize>,
+	pub max_clients: Option<usize>,
+	pub max_publishers: Option<usize>,
+	pub max_subscribers: Option<usize>,
+}
+
+/// How many connections a registration has, counting dropped ones that can still be resumed
+#[derive(Serialize, Clone, Copy, Debug, Default)]
+pub struct ConnectionCounts {
+	pub connections: usize,
+	pub hosts: usize,
+	pub clients: usize,
+	pub publishers: usize,
+	pub subscribers: usize,
+}
+
+impl ConnectionCounts {
+	pub fn add(&mut self, sock_type: SocketType) {
+		self.connections += 1;
+
+		match sock_type {
+			SocketType::Host => self.hosts += 1,
+			SocketType::Client => self.clients += 1,
+			SocketType::Publisher => self.publishers += 1,
+			SocketType::Subscriber => self.subscribers += 1,
+			SocketType::Socket => (),
+		}
+	}
+}
+
+impl ConnectionLimits {
+	/// Whether another connection of `sock_type` would go over any of these limits
+	pub fn is_full(&self, counts: &ConnectionCounts, sock_type: SocketType) -> bool {
+		let over = |max: Option<usize>, count: usize| matches!(max, Some(max) if count >= max);
+
+		over(self.max_connections, counts.connections)
+			|| match sock_type {
+				SocketType::Host => over(self.max_hosts, counts.hosts),
+				SocketType::Client => over(self.max_clients, counts.clients),
+				SocketType::Publisher => over(self.max_publishers, counts.publishers),
+				SocketType::Subscriber => over(self.max_subscribers, counts.subscribers),
+				SocketType::Socket => false,
+			}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn counts(sock_types: &[SocketType]) -> ConnectionCounts {
+		let mut counts = ConnectionCounts::default();
+		for &sock_type in sock_types {
+			counts.add(sock_type);
+		}
+		counts
+	}
+
+	#[test]
+	fn limits_each_socket_type() {
+		let limits = ConnectionLimits {
+			max_hosts: Some(1),
+			..ConnectionLimits::default()
+		};
+		let counts = counts(&[SocketType::Host, SocketType::Client]);
+
+		assert!(limits.is_full(&counts, SocketType::Host));
+		assert!(!limits.is_full(&counts, SocketType::Client));
+	}
+
+	#[test]
+	fn limits_the_total() {
+		let limits = ConnectionLimits {
+			max_connections: Some(2),
+			max_hosts: Some(5),
+			..ConnectionLimits::default()
+		};
+
+		assert!(!limits.is_full(&counts(&[SocketType::Client]), SocketType::Host));
+		assert!(limits.is_full(
+			&counts(&[SocketType::Client, SocketType::Client]),
+			SocketType::Host
+		));
+	}
+
+	#[test]
+	fn unlimited_by_default() {
+		let counts = counts(&[SocketType::Socket; 100]);
+		assert!(!ConnectionLimits::default().is_full(&counts, SocketType::Socket));
+	}
+}
@@ -0,0 +1,113 @@
+use crate::{config::Config, register::Rejections};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// The longest that `ping_interval` and `pong_timeout` can be, on the server or for a single
+/// registration. Anything longer isn't much of a keepalive, and much longer than this couldn't be
+/// added to the current time.
+pub const MAX_KEEPALIVE_SECS: u64 = 24 * 60 * 60;
+
+/// How a registration's connections are pinged, to find the ones that died without closing.
+/// `None` means that the server's own setting is used.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+pub struct Keepalive {
+	pub ping_interval: Option<u64>,
+	pub pong_timeout: Option<u64>,
+	pub max_missed_pongs: Option<u32>,
+}
+
+/// The settings that a connection is actually pinged with, once the server's defaults have
+/// been filled in
+#[derive(Clone, Copy, Debug)]
+pub struct KeepaliveSettings {
+	pub ping_interval: Duration,
+	pub pong_timeout: Duration,
+	/// 0 means that connections are never disconnected for missing pongs
+	pub max_missed_pongs: u32,
+}
+
+impl Keepalive {
+	pub fn validate(&self) -> Result<(), Rejections> {
+		for (name, secs) in [
+			("ping_interval", self.ping_interval),
+			("pong_timeout", self.pong_timeout),
+		] {
+			match secs {
+				Some(0) => {
+					return Err(Rejections::InvalidKeepalive(format!(
+						"{} must be at least 1 second",
+						name
+					)))
+				}
+				Some(secs) if secs > MAX_KEEPALIVE_SECS => {
+					return Err(Rejections::InvalidKeepalive(format!(
+						"{} can be at most {} seconds",
+						name, MAX_KEEPALIVE_SECS
+					)))
+				}
+				_ => (),
+			}
+		}
+
+		Ok(())
+	}
+
+	pub fn settings(&self, conf: &Config) -> KeepaliveSettings {
+		KeepaliveSettings {
+			ping_interval: Duration::from_secs(self.ping_interval.unwrap_or(conf.ping_interval)),
+			pong_timeout: Duration::from_secs(self.pong_timeout.unwrap_or(conf.pong_timeout)),
+			max_missed_pongs: self.max_missed_pongs.unwrap_or(conf.max_missed_pongs),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn keepalive(ping_interval: Option<u64>, pong_timeout: Option<u64>) -> Keepalive {
+		Keepalive {
+			ping_interval,
+			pong_timeout,
+			max_missed_pongs: None,
+		}
+	}
+
+	#[test]
+	fn validate_bounds_both_durations() {
+		assert!(keepalive(None, None).validate().is_ok());
+		assert!(keepalive(Some(1), Some(MAX_KEEPALIVE_SECS))
+			.validate()
+			.is_ok());
+
+		for (ping_interval, pong_timeout) in [
+			(Some(0), None),
+			(None, Some(0)),
+			(Some(MAX_KEEPALIVE_SECS + 1), None),
+			(None, Some(u64::MAX)),
+		] {
+			assert!(matches!(
+				keepalive(ping_interval, pong_timeout).validate(),
+				Err(Rejections::InvalidKeepalive(_))
+			));
+		}
+	}
+
+	#[test]
+	fn settings_fall_back_to_the_server() {
+		let conf = Config::default();
+		let settings = Keepalive {
+			ping_interval: Some(5),
+			pong_timeout: None,
+			max_missed_pongs: Some(0),
+		}
+		.settings(&conf);
+
+		assert_eq!(settings.ping_interval, Duration::from_secs(5));
+		assert_eq!(
+			settings.pong_timeout,
+			Duration::from_secs(conf.pong_timeout)
+		);
+		assert_eq!(settings.max_missed_pongs, 0);
+	}
+}
@@ -0,0 +1,162 @@
+use crate::{config::Config, register::Rejections};
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use warp::ws::Message;
+
+/// Which kinds of data messages a registration's connections may send
+#[derive(Serialize, Deserialize, Eq, PartialEq, Clone, Copy, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageTypes {
+	#[default]
+	Any,
+	Text,
+	Binary,
+}
+
+/// How large the messages sent to a registration may be, and what kind they must be. `None`
+/// means that the server's own limit is used.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+pub struct MessageLimits {
+	pub max_message_size: Option<usize>,
+	pub max_frame_size: Option<usize>,
+	#[serde(default)]
+	pub message_types: MessageTypes,
+}
+
+/// The limits that a connection's messages are actually held to, once the server's defaults
+/// have been filled in
+#[derive(Clone, Copy, Debug)]
+pub struct MessageLimitSettings {
+	pub max_message_size: usize,
+	pub max_frame_size: usize,
+	pub message_types: MessageTypes,
+}
+
+impl MessageLimits {
+	/// Registrations can lower the server's limits, but never raise them
+	pub fn validate(&self, conf: &Config) -> Result<(), Rejections> {
+		for (name, size, max) in [
+			(
+				"max_message_size",
+				self.max_message_size,
+				conf.max_message_size,
+			),
+			("max_frame_size", self.max_frame_size, conf.max_frame_size),
+		] {
+			match size {
+				Some(0) => {
+					return Err(Rejections::InvalidMessageLimits(format!(
+						"{} must be at least 1 byte",
+						name
+					)))
+				}
+				Some(size) if size > max => {
+					return Err(Rejections::InvalidMessageLimits(format!(
+						"{} can be at most {} bytes",
+						name, max
+					)))
+				}
+				_ => (),
+			}
+		}
+
+		Ok(())
+	}
+
+	pub fn settings(&self, conf: &Config) -> MessageLimitSettings {
+		MessageLimitSettings {
+			max_message_size: self.max_message_size.unwrap_or(conf.max_message_size),
+			max_frame_size: self.max_frame_size.unwrap_or(conf.max_frame_size),
+			message_types: self.message_types,
+		}
+	}
+}
+
+impl MessageLimitSettings {
+	/// Whether a message is of a kind that this registration accepts. Only text and binary
+	/// messages are limited, so control frames are always allowed.
+	pub fn allows(&self, msg: &Message) -> bool {
+		match self.message_types {
+			MessageTypes::Any => true,
+			MessageTypes::Text => !msg.is_binary(),
+			MessageTypes::Binary => !msg.is_text(),
+		}
+	}
+}
+
+/// Whether an error from reading a websocket is tungstenite's `Error::Capacity`, which is what it
+/// returns when a message or frame is over `max_message_size` or `max_frame_size`. warp wraps
+/// tungstenite's errors in its own opaque `warp::Error`, so the only way to tell is by the
+/// message that tungstenite gives it, which has started with "Space limit exceeded" since at
+/// least tungstenite 0.14. If that ever changes, oversized messages are still refused, but the
+/// connection isn't closed with `MESSAGE_TOO_BIG` first.
+pub fn is_over_size_limit(err: &impl Display) -> bool {
+	err.to_string().starts_with("Space limit exceeded")
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn settings(message_types: MessageTypes) -> MessageLimitSettings {
+		MessageLimitSettings {
+			max_message_size: 1024,
+			max_frame_size: 1024,
+			message_types,
+		}
+	}
+
+	#[test]
+	fn allows_only_the_right_data_messages() {
+		let (text, binary) = (Message::text("hi"), Message::binary(vec![1, 2]));
+
+		assert!(settings(MessageTypes::Any).allows(&text));
+		assert!(settings(MessageTypes::Any).allows(&binary));
+		assert!(settings(MessageTypes::Text).allows(&text));
+		assert!(!settings(MessageTypes::Text).allows(&binary));
+		assert!(!settings(MessageTypes::Binary).allows(&text));
+		assert!(settings(MessageTypes::Binary).allows(&binary));
+	}
+
+	#[test]
+	fn always_allows_control_frames() {
+		for types in [MessageTypes::Text, MessageTypes::Binary] {
+			assert!(settings(types).allows(&Message::ping(b"hi".to_vec())));
+			assert!(settings(types).allows(&Message::pong(b"hi".to_vec())));
+			assert!(settings(types).allows(&Message::close()));
+		}
+	}
+
+	#[test]
+	fn validate_only_lowers_the_servers_limits() {
+		let conf = Config::default();
+		let limits = |max_message_size, max_frame_size| MessageLimits {
+			max_message_size,
+			max_frame_size,
+			message_types: MessageTypes::Any,
+		};
+
+		assert!(limits(None, None).validate(&conf).is_ok());
+		assert!(limits(Some(1), Some(conf.max_frame_size))
+			.validate(&conf)
+			.is_ok());
+		assert!(limits(Some(0), None).validate(&conf).is_err());
+		assert!(limits(None, Some(conf.max_frame_size + 1))
+			.validate(&conf)
+			.is_err());
+
+		let settings = limits(Some(10), None).settings(&conf);
+		assert_eq!(settings.max_message_size, 10);
+		assert_eq!(settings.max_frame_size, conf.max_frame_size);
+	}
+
+	#[test]
+	fn recognizes_tungstenites_capacity_error() {
+		assert!(is_over_size_limit(
+			&"Space limit exceeded: Message too long: 2000 > 1024"
+		));
+		assert!(!is_over_size_limit(
+			&"WebSocket protocol error: Connection reset without closing handshake"
+		));
+	}
+}
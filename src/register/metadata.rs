@@ -0,0 +1,120 @@
+use crate::register::Rejections;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+/// The longest that a registration's name may be, in bytes
+pub const MAX_NAME_LEN: usize = 64;
+/// The most tags that a registration may have
+pub const MAX_TAGS: usize = 16;
+/// The longest that each tag may be, in bytes
+pub const MAX_TAG_LEN: usize = 32;
+/// The longest that a registration's app version may be, in bytes
+pub const MAX_APP_VERSION_LEN: usize = 32;
+
+/// Whatever the client wants to say about a registration. The router doesn't use any of it; it
+/// only stores it so that it can be looked up later.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Metadata {
+	#[serde(default)]
+	pub name: Option<String>,
+	#[serde(default)]
+	pub tags: Vec<String>,
+	#[serde(default)]
+	pub app_version: Option<String>,
+	/// Anything else, as long as the whole thing fits in `max_metadata_bytes`
+	#[serde(default)]
+	pub extra: Map<String, Value>,
+}
+
+impl Metadata {
+	/// Makes sure that this isn't going to take up more than its fair share of memory
+	pub fn validate(&self, max_bytes: usize) -> Result<(), Rejections> {
+		let invalid = |msg: String| Err(Rejections::InvalidMetadata(msg));
+
+		if matches!(self.name, Some(ref name) if name.len() > MAX_NAME_LEN) {
+			return invalid(format!("name can't be longer than {} bytes", MAX_NAME_LEN));
+		}
+
+		if self.tags.len() > MAX_TAGS {
+			return invalid(format!("there can't be more than {} tags", MAX_TAGS));
+		}
+
+		if self.tags.iter().any(|t| t.len() > MAX_TAG_LEN) {
+			return invalid(format!("tags can't be longer than {} bytes", MAX_TAG_LEN));
+		}
+
+		if matches!(self.app_version, Some(ref v) if v.len() > MAX_APP_VERSION_LEN) {
+			return invalid(format!(
+				"app_version can't be longer than {} bytes",
+				MAX_APP_VERSION_LEN
+			));
+		}
+
+		let len = serde_json::to_vec(self)
+			.map(|v| v.len())
+			.unwrap_or(usize::MAX);
+		if len > max_bytes {
+			return invalid(format!(
+				"metadata can't be larger than {} bytes of JSON",
+				max_bytes
+			));
+		}
+
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn invalid(metadata: &Metadata, max_bytes: usize) -> bool {
+		matches!(
+			metadata.validate(max_bytes),
+			Err(Rejections::InvalidMetadata(_))
+		)
+	}
+
+	#[test]
+	fn limits_each_field() {
+		let long = |len| "x".repeat(len);
+
+		assert!(Metadata::default().validate(1024).is_ok());
+
+		for metadata in [
+			Metadata {
+				name: Some(long(MAX_NAME_LEN + 1)),
+				..Metadata::default()
+			},
+			Metadata {
+				tags: vec![String::new(); MAX_TAGS + 1],
+				..Metadata::default()
+			},
+			Metadata {
+				tags: vec![long(MAX_TAG_LEN + 1)],
+				..Metadata::default()
+			},
+			Metadata {
+				app_version: Some(long(MAX_APP_VERSION_LEN + 1)),
+				..Metadata::default()
+			},
+		] {
+			assert!(invalid(&metadata, usize::MAX));
+		}
+	}
+
+	#[test]
+	fn limits_the_whole_thing() {
+		let mut metadata = Metadata {
+			name: Some("lobby".to_owned()),
+			..Metadata::default()
+		};
+		metadata
+			.extra
+			.insert("notes".to_owned(), Value::String("x".repeat(100)));
+
+		let len = serde_json::to_vec(&metadata).unwrap().len();
+		assert!(metadata.validate(len).is_ok());
+		assert!(invalid(&metadata, len - 1));
+	}
+}
@@ -1,9 +1,23 @@
+pub use connection_limits::*;
+pub use keepalive::*;
+pub use message_limits::*;
+pub use metadata::*;
+pub use rate_limits::*;
+pub use reaper::*;
 pub use register_request::*;
 pub use registration::*;
 pub use rejections::*;
 pub use remove_request::*;
+pub use rotate_request::*;
 
+mod connection_limits;
+mod keepalive;
+mod message_limits;
+mod metadata;
+mod rate_limits;
+mod reaper;
 mod register_request;
 mod registration;
 mod rejections;
 mod remove_request;
+mod rotate_request;
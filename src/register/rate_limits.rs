@@ -0,0 +1,411 @@
+use crate::{config::Config, limits::Bucket, register::Rejections};
+use serde::{Deserialize, Serialize};
+use std::{
+	str::FromStr,
+	sync::{Arc, Mutex, MutexGuard},
+	time::Duration,
+};
+
+/// What to do with a message that a connection sent faster than its rate limits allow
+#[derive(Serialize, Deserialize, Eq, PartialEq, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitPolicy {
+	/// Drop the message without forwarding it
+	Drop,
+	/// Stop reading from the connection until the message is allowed, and then forward it
+	Delay,
+	/// Close the connection
+	Disconnect,
+}
+
+impl FromStr for RateLimitPolicy {
+	type Err = ();
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"drop" => Ok(RateLimitPolicy::Drop),
+			"delay" => Ok(RateLimitPolicy::Delay),
+			"disconnect" => Ok(RateLimitPolicy::Disconnect),
+			_ => Err(()),
+		}
+	}
+}
+
+impl RateLimitPolicy {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			RateLimitPolicy::Drop => "drop",
+			RateLimitPolicy::Delay => "delay",
+			RateLimitPolicy::Disconnect => "disconnect",
+		}
+	}
+}
+
+/// How quickly a registration's connections may send messages. `None` means that the server's
+/// own setting is used.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+pub struct RateLimits {
+	pub message_rate_limit: Option<f64>,
+	pub message_rate_burst: Option<u64>,
+	pub byte_rate_limit: Option<f64>,
+	pub byte_rate_burst: Option<u64>,
+	pub total_message_rate_limit: Option<f64>,
+	pub total_byte_rate_limit: Option<f64>,
+	pub rate_limit_policy: Option<RateLimitPolicy>,
+}
+
+/// The rate limits that a connection is actually held to, once the server's defaults have been
+/// filled in. A rate of 0 means that it isn't limited.
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimitSettings {
+	pub message_rate: f64,
+	pub message_burst: f64,
+	pub byte_rate: f64,
+	pub byte_burst: f64,
+	pub policy: RateLimitPolicy,
+}
+
+impl RateLimits {
+	/// Registrations can lower the server's rate limits and bursts, but never raise or remove
+	/// them
+	pub fn validate(&self, conf: &Config) -> Result<(), Rejections> {
+		for (name, rate, max) in [
+			(
+				"message_rate_limit",
+				self.message_rate_limit,
+				conf.message_rate_limit,
+			),
+			(
+				"byte_rate_limit",
+				self.byte_rate_limit,
+				conf.byte_rate_limit,
+			),
+			(
+				"total_message_rate_limit",
+				self.total_message_rate_limit,
+				conf.total_message_rate_limit,
+			),
+			(
+				"total_byte_rate_limit",
+				self.total_byte_rate_limit,
+				conf.total_byte_rate_limit,
+			),
+		] {
+			match rate {
+				Some(rate) if !(rate > 0.0 && rate.is_finite()) => {
+					return Err(Rejections::InvalidRateLimits(format!(
+						"{} must be a positive number",
+						name
+					)))
+				}
+				Some(rate) if max > 0.0 && rate > max => {
+					return Err(Rejections::InvalidRateLimits(format!(
+						"{} can be at most {}",
+						name, max
+					)))
+				}
+				_ => (),
+			}
+		}
+
+		for (name, burst, max) in [
+			(
+				"message_rate_burst",
+				self.message_rate_burst,
+				conf.message_rate_burst,
+			),
+			(
+				"byte_rate_burst",
+				self.byte_rate_burst,
+				conf.byte_rate_burst,
+			),
+		] {
+			match burst {
+				Some(0) => {
+					return Err(Rejections::InvalidRateLimits(format!(
+						"{} must be at least 1",
+						name
+					)))
+				}
+				Some(burst) if burst > max => {
+					return Err(Rejections::InvalidRateLimits(format!(
+						"{} can be at most {}",
+						name, max
+					)))
+				}
+				_ => (),
+			}
+		}
+
+		Ok(())
+	}
+
+	/// The limits that each of the registration's connections is held to on its own
+	pub fn settings(&self, conf: &Config) -> RateLimitSettings {
+		RateLimitSettings {
+			message_rate: self.message_rate_limit.unwrap_or(conf.message_rate_limit),
+			message_burst: self.message_rate_burst.unwrap_or(conf.message_rate_burst) as f64,
+			byte_rate: self.byte_rate_limit.unwrap_or(conf.byte_rate_limit),
+			byte_burst: self.byte_rate_burst.unwrap_or(conf.byte_rate_burst) as f64,
+			policy: self.rate_limit_policy.unwrap_or(conf.rate_limit_policy),
+		}
+	}
+
+	/// The limits that all of the registration's connections are held to together. They share
+	/// the same bursts as each connection does on its own.
+	pub fn total_settings(&self, conf: &Config) -> RateLimitSettings {
+		RateLimitSettings {
+			message_rate: self
+				.total_message_rate_limit
+				.unwrap_or(conf.total_message_rate_limit),
+			byte_rate: self
+				.total_byte_rate_limit
+				.unwrap_or(conf.total_byte_rate_limit),
+			..self.settings(conf)
+		}
+	}
+}
+
+/// Keeps track of how quickly a single connection is sending messages, or all of a
+/// registration's connections together, in a `SharedRateLimiter`
+pub struct MessageRateLimiter {
+	settings: RateLimitSettings,
+	messages: Bucket,
+	bytes: Bucket,
+}
+
+impl MessageRateLimiter {
+	pub fn new(settings: RateLimitSettings) -> MessageRateLimiter {
+		MessageRateLimiter {
+			settings,
+			messages: Bucket::new(settings.message_burst),
+			bytes: Bucket::new(settings.byte_burst),
+		}
+	}
+
+	pub fn policy(&self) -> RateLimitPolicy {
+		self.settings.policy
+	}
+
+	/// Counts a message of `len` bytes against the limits if it's allowed, or returns how long
+	/// it'll be until it is. Nothing is counted unless both limits allow it, so a message that's
+	/// dropped doesn't use up either of them.
+	pub fn check(&mut self, len: usize) -> Result<(), Duration> {
+		let s = self.settings;
+
+		// a message that's larger than the whole burst would never be allowed otherwise, so it
+		// just has to wait for the bucket to fill up all the way
+		let len = (len as f64).min(s.byte_burst);
+
+		let mut wait: f64 = 0.0;
+
+		if s.message_rate > 0.0 {
+			self.messages.refill(s.message_rate, s.message_burst);
+			wait = wait.max(self.messages.wait_for(1.0, s.message_rate));
+		}
+
+		if s.byte_rate > 0.0 {
+			self.bytes.refill(s.byte_rate, s.byte_burst);
+			wait = wait.max(self.bytes.wait_for(len, s.byte_rate));
+		}
+
+		if wait > 0.0 {
+			// a tiny enough rate can make the wait too long to fit in a `Duration`, and that's
+			// the same as waiting forever
+			return Err(Duration::try_from_secs_f64(wait).unwrap_or(Duration::MAX));
+		}
+
+		if s.message_rate > 0.0 {
+			self.messages.take(1.0);
+		}
+
+		if s.byte_rate > 0.0 {
+			self.bytes.take(len);
+		}
+
+		Ok(())
+	}
+}
+
+/// A rate limiter that all of a registration's connections share. It's only set up once one of
+/// them starts sending, since the server's settings are needed to fill in its limits, and it's
+/// left empty if the registration doesn't have any limits of its own.
+#[derive(Clone, Default)]
+pub struct SharedRateLimiter(Arc<Mutex<Option<MessageRateLimiter>>>);
+
+impl SharedRateLimiter {
+	/// Sets up the limiter with `settings`, unless a connection already has
+	pub fn init(&self, settings: RateLimitSettings) {
+		let mut limiter = self.lock();
+
+		if limiter.is_none() && (settings.message_rate > 0.0 || settings.byte_rate > 0.0) {
+			*limiter = Some(MessageRateLimiter::new(settings));
+		}
+	}
+
+	/// Like `MessageRateLimiter::check`, but for every connection in the registration at once.
+	/// Everything is allowed if the registration doesn't have any limits of its own.
+	pub fn check(&self, len: usize) -> Result<(), Duration> {
+		match self.lock().as_mut() {
+			Some(limiter) => limiter.check(len),
+			None => Ok(()),
+		}
+	}
+
+	// a panic while holding this lock can't leave the buckets in an invalid state, so there's no
+	// reason to stop using them if that happens
+	fn lock(&self) -> MutexGuard<'_, Option<MessageRateLimiter>> {
+		self.0.lock().unwrap_or_else(|p| p.into_inner())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn settings(message_rate: f64, message_burst: f64) -> RateLimitSettings {
+		RateLimitSettings {
+			message_rate,
+			message_burst,
+			byte_rate: 0.0,
+			byte_burst: 1024.0,
+			policy: RateLimitPolicy::Drop,
+		}
+	}
+
+	#[test]
+	fn policy_round_trips() {
+		for policy in [
+			RateLimitPolicy::Drop,
+			RateLimitPolicy::Delay,
+			RateLimitPolicy::Disconnect,
+		] {
+			assert_eq!(policy.as_str().parse(), Ok(policy));
+		}
+
+		assert!("block".parse::<RateLimitPolicy>().is_err());
+	}
+
+	#[test]
+	fn validate_only_allows_lowering_the_server() {
+		let mut conf = Config::default();
+		conf.message_rate_limit = 10.0;
+
+		let ok = RateLimits {
+			message_rate_limit: Some(5.0),
+			message_rate_burst: Some(conf.message_rate_burst),
+			byte_rate_limit: Some(1e9),
+			byte_rate_burst: Some(1),
+			total_message_rate_limit: Some(20.0),
+			..RateLimits::default()
+		};
+		assert!(ok.validate(&conf).is_ok());
+
+		for limits in [
+			RateLimits {
+				message_rate_limit: Some(11.0),
+				..RateLimits::default()
+			},
+			RateLimits {
+				byte_rate_limit: Some(f64::NAN),
+				..RateLimits::default()
+			},
+			RateLimits {
+				total_byte_rate_limit: Some(0.0),
+				..RateLimits::default()
+			},
+			RateLimits {
+				message_rate_burst: Some(0),
+				..RateLimits::default()
+			},
+			RateLimits {
+				byte_rate_burst: Some(conf.byte_rate_burst + 1),
+				..RateLimits::default()
+			},
+		] {
+			assert!(matches!(
+				limits.validate(&conf),
+				Err(Rejections::InvalidRateLimits(_))
+			));
+		}
+	}
+
+	#[test]
+	fn settings_fall_back_to_the_server() {
+		let mut conf = Config::default();
+		conf.total_byte_rate_limit = 100.0;
+
+		let limits = RateLimits {
+			message_rate_burst: Some(3),
+			total_message_rate_limit: Some(2.0),
+			..RateLimits::default()
+		};
+
+		let own = limits.settings(&conf);
+		assert_eq!(own.message_burst, 3.0);
+		assert_eq!(own.byte_burst, conf.byte_rate_burst as f64);
+		assert_eq!(own.message_rate, conf.message_rate_limit);
+
+		let total = limits.total_settings(&conf);
+		assert_eq!(total.message_rate, 2.0);
+		assert_eq!(total.message_burst, 3.0);
+		assert_eq!(total.byte_rate, 100.0);
+	}
+
+	#[test]
+	fn check_allows_the_burst_and_then_waits() {
+		let mut limiter = MessageRateLimiter::new(settings(1.0, 2.0));
+
+		assert!(limiter.check(10).is_ok());
+		assert!(limiter.check(10).is_ok());
+
+		let wait = limiter.check(10).unwrap_err();
+		assert!(wait > Duration::ZERO && wait <= Duration::from_secs(1));
+	}
+
+	#[test]
+	fn check_counts_bytes_up_to_the_burst() {
+		let mut limiter = MessageRateLimiter::new(RateLimitSettings {
+			byte_rate: 100.0,
+			..settings(0.0, 1.0)
+		});
+
+		// larger than the burst, so it only has to wait for a full bucket
+		assert!(limiter.check(4096).is_ok());
+		assert!(limiter.check(1).is_err());
+	}
+
+	#[test]
+	fn tiny_rates_wait_instead_of_panicking() {
+		let mut limiter = MessageRateLimiter::new(settings(1e-300, 1.0));
+
+		assert!(limiter.check(0).is_ok());
+		assert_eq!(limiter.check(0), Err(Duration::MAX));
+	}
+
+	#[test]
+	fn shared_limits_apply_across_connections() {
+		let shared = SharedRateLimiter::default();
+		shared.init(settings(1.0, 2.0));
+		// a later connection mustn't reset the buckets
+		shared.init(settings(1.0, 2.0));
+
+		let other = shared.clone();
+
+		assert!(shared.check(0).is_ok());
+		assert!(other.check(0).is_ok());
+		assert!(shared.check(0).is_err());
+		assert!(other.check(0).is_err());
+	}
+
+	#[test]
+	fn unlimited_totals_allow_everything() {
+		let shared = SharedRateLimiter::default();
+		shared.init(settings(0.0, 1.0));
+
+		assert!(shared.lock().is_none());
+		for _ in 0..100 {
+			assert!(shared.check(1024).is_ok());
+		}
+	}
+}
@@ -0,0 +1,53 @@
+use crate::{limits, sockets::close_codes, Registrations, CONFIG};
+use chrono::Utc;
+use std::time::Duration;
+use tracing::{debug, error, info, info_span, Instrument};
+
+/// Spawns a task that periodically removes every registration that has expired, either because
+/// it outlived its ttl or because nobody has been connected to it for longer than `idle_ttl`
+/// (or `unused_ttl`, if nobody has ever connected to it and there's no `idle_ttl`).
+/// It also forgets any rate limits and lockouts that have run out while it's at it.
+pub fn spawn_reaper(registrations: Registrations) {
+	tokio::spawn(
+		async move {
+			let conf = CONFIG.read().await;
+			let (reap_interval, idle_ttl) = (conf.reap_interval, conf.idle_ttl);
+			let unused_ttl = Some(conf.unused_ttl).filter(|&ttl| ttl > 0);
+			drop(conf);
+
+			let mut interval = tokio::time::interval(Duration::from_secs(reap_interval));
+
+			loop {
+				interval.tick().await;
+				reap(&registrations, idle_ttl, unused_ttl).await;
+				limits::prune().await;
+			}
+		}
+		.instrument(info_span!("reaper")),
+	);
+}
+
+async fn reap(registrations: &Registrations, idle_ttl: Option<u64>, unused_ttl: Option<u64>) {
+	debug!("Checking for expired registrations...");
+
+	let now = Utc::now().timestamp();
+	let mut regs = registrations.write().await;
+
+	let mut expired = Vec::new();
+	for (id, reg) in regs.iter() {
+		if reg.is_expired(now, idle_ttl, unused_ttl).await {
+			expired.push(id.to_owned());
+		}
+	}
+
+	for id in expired {
+		if let Some(reg) = regs.remove(&id) {
+			info!(reg_id = %id, "Registration expired; removing...");
+
+			reg.close(close_codes::EXPIRED, "Registration expired", "expired")
+				.await;
+		} else {
+			error!(reg_id = %id, "Expired registration disappeared before removal");
+		}
+	}
+}
@@ -1,9 +1,39 @@
+use crate::{
+	register::{MessageTypes, Metadata, RateLimitPolicy},
+	secret::Secret,
+};
 use serde::Deserialize;
 
 #[derive(Deserialize)]
 pub struct RegisterRequest {
-	pub key: String,
-	pub host_key: String,
+	pub key: Secret,
+	pub host_key: Secret,
 	pub reg_type: String,
 	pub id_req: Option<String>,
+	pub ttl: Option<u64>,
+	pub presence: Option<bool>,
+	pub resume: Option<bool>,
+	pub max_connections: Option<usize>,
+	pub max_hosts: Option<usize>,
+	pub max_clients: Option<usize>,
+	pub max_publishers: Option<usize>,
+	pub max_subscribers: Option<usize>,
+	pub public: Option<bool>,
+	pub host_auth: Option<bool>,
+	pub ping_interval: Option<u64>,
+	pub pong_timeout: Option<u64>,
+	pub max_missed_pongs: Option<u32>,
+	pub max_message_size: Option<usize>,
+	pub max_frame_size: Option<usize>,
+	pub message_types: Option<MessageTypes>,
+	pub message_rate_limit: Option<f64>,
+	pub message_rate_burst: Option<u64>,
+	pub byte_rate_limit: Option<f64>,
+	pub byte_rate_burst: Option<u64>,
+	pub total_message_rate_limit: Option<f64>,
+	pub total_byte_rate_limit: Option<f64>,
+	pub rate_limit_policy: Option<RateLimitPolicy>,
+	/// Can only be sent in the JSON body of a `POST`, since it doesn't fit in a query string
+	#[serde(default)]
+	pub metadata: Metadata,
 }
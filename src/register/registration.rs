@@ -1,279 +1,806 @@
-use crate::{config::*, CONFIG};
 use crate::{
-	connections::Connection, err, log, log_vbs, register::*, sockets::SocketType, Registrations,
+	connections::{Connection, DetachedConnection},
+	hashing::{self, HashedKey},
+	limits, metrics,
+	register::*,
+	secret::Secret,
+	shutdown,
+	sockets::{close_codes, Command, ConnectOptions, ControlMessage, Envelope, Peer, SocketType},
+	store::RegistrationRecord,
+	Registrations,
 };
+use crate::{CONFIG, STORE};
+use chrono::Utc;
 use futures_locks::RwLock;
 use futures_util::{
 	stream::{SplitSink, SplitStream},
-	SinkExt, StreamExt,
+	StreamExt,
 };
-use std::{collections::hash_map::Entry, result::Result, sync::Arc, vec::Vec, time::Duration};
+use serde::{Deserialize, Serialize};
+use std::{
+	collections::{hash_map::Entry, VecDeque},
+	net::SocketAddr,
+	result::Result,
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		Arc,
+	},
+	time::Duration,
+	vec::Vec,
+};
+use tokio::time::{Instant, MissedTickBehavior};
+use tracing::{debug, error, info, trace, warn, Instrument};
 use uuid::Uuid;
 use warp::{
-	reject,
 	ws::{Message, WebSocket},
 	Rejection, Reply,
 };
 
 pub struct Registration {
 	pub uuid: String,
-	pub key: String,
-	pub host_key: String,
+	pub key: Arc<HashedKey>,
+	pub host_key: Arc<HashedKey>,
 	pub reg_type: RegistrationType,
 	pub connections: Arc<RwLock<Vec<Connection>>>,
 	pub destroy: Arc<RwLock<bool>>,
+	pub expires_at: Option<i64>,
+	pub last_active: Arc<RwLock<i64>>,
+	pub presence: bool,
+	pub resume: bool,
+	pub detached: Arc<RwLock<Vec<DetachedConnection>>>,
+	pub limits: ConnectionLimits,
+	pub metadata: Metadata,
+	pub public: bool,
+	pub host_auth: bool,
+	pub locked: Arc<RwLock<bool>>,
+	pub keepalive: Keepalive,
+	pub message_limits: MessageLimits,
+	pub rate_limits: RateLimits,
+	pub rate_limiter: SharedRateLimiter,
+	/// Whether anyone has ever connected to this registration
+	pub used: bool,
 }
 
 impl Registration {
 	pub async fn new(
-		unhashed_key: &str,
-		unhashed_host_key: &str,
+		req: RegisterRequest,
 		reg_type: RegistrationType,
-		id_req: Option<String>,
-		registrations: Registrations,
 	) -> Result<Registration, Rejections> {
 		let conf = CONFIG.read().await;
-		let (out, vbs, reject) = (!conf.quiet, conf.verbose, conf.reject_no_id);
-		let secret_key_bytes = conf.secret_key.as_bytes().to_vec();
+		let reject = conf.reject_no_id;
+		let (default_ttl, max_ttl) = (conf.default_ttl, conf.max_ttl);
+		let secret_key_bytes = conf.secret_key.expose().as_bytes().to_vec();
+		let max_metadata_bytes = conf.max_metadata_bytes;
+
+		let message_limits = MessageLimits {
+			max_message_size: req.max_message_size,
+			max_frame_size: req.max_frame_size,
+			message_types: req.message_types.unwrap_or_default(),
+		};
+		message_limits.validate(&conf)?;
+
+		let rate_limits = RateLimits {
+			message_rate_limit: req.message_rate_limit,
+			message_rate_burst: req.message_rate_burst,
+			byte_rate_limit: req.byte_rate_limit,
+			byte_rate_burst: req.byte_rate_burst,
+			total_message_rate_limit: req.total_message_rate_limit,
+			total_byte_rate_limit: req.total_byte_rate_limit,
+			rate_limit_policy: req.rate_limit_policy,
+		};
+		rate_limits.validate(&conf)?;
 		drop(conf);
 
-		log!(
-			out,
-			Color::Green,
-			"Attempting to create new registration..."
-		);
+		info!("Attempting to create new registration...");
 
-		let config = argon2::Config::default();
+		req.metadata.validate(max_metadata_bytes)?;
 
-		let key = argon2::hash_encoded(
-			unhashed_key.as_bytes(),
-			&secret_key_bytes,
-			&config
-		).map_err(|_| Rejections::UnhashableKey)?;
+		let keepalive = Keepalive {
+			ping_interval: req.ping_interval,
+			pong_timeout: req.pong_timeout,
+			max_missed_pongs: req.max_missed_pongs,
+		};
+		keepalive.validate()?;
 
-		let host_key = argon2::hash_encoded(
-			unhashed_host_key.as_bytes(),
-			&secret_key_bytes,
-			&config,
-		).map_err(|_| Rejections::UnhashableKey)?;
+		let (key, host_key) = futures_util::future::join(
+			hashing::hash(req.key.expose().to_owned(), secret_key_bytes.clone()),
+			hashing::hash(req.host_key.expose().to_owned(), secret_key_bytes),
+		)
+		.await;
 
-		log_vbs!(vbs, out, "Verified keys...");
+		let key = key.map_err(|err| {
+			error!("Failed to hash key: {}", err);
+			Rejections::UnhashableKey
+		})?;
+		let host_key = host_key.map_err(|err| {
+			error!("Failed to hash host key: {}", err);
+			Rejections::UnhashableKey
+		})?;
 
-		let has_id_req = id_req.is_some();
+		debug!("Hashed keys...");
 
 		// we have to make sure that the id they entered is greater than 7 characters
 		// so that it doesn't cause a crash when uuid_str is truncated
-		let uuid = match id_req {
+		let uuid = match req.id_req {
 			Some(id) if id.len() == 8 => id,
 			Some(_) if reject => return Err(Rejections::IncorrectLengthID),
 			_ => Uuid::new_v4().to_simple().to_string(),
 		};
 
-		let destroy = Arc::new(RwLock::new(false));
-
-		let mut uuid_str = uuid[..8].to_owned();
+		if let (Some(req_ttl), Some(max)) = (req.ttl, max_ttl) {
+			if req_ttl > max && reject {
+				return Err(Rejections::TTLTooLong);
+			}
+		}
 
-		log_vbs!(
-			vbs,
-			out,
-			"Created shortened uuid \x1b[1m{}\x1b[0m",
-			uuid_str
-		);
+		// if the server has a max ttl, every registration has to expire at some point, even if
+		// it didn't ask to
+		let ttl = match (req.ttl.or(default_ttl), max_ttl) {
+			(Some(ttl), Some(max)) => Some(ttl.min(max)),
+			(None, Some(max)) => Some(max),
+			(ttl, None) => ttl,
+		};
 
-		let reg = registrations.read().await;
+		let now = Utc::now().timestamp();
+		let expires_at = match ttl {
+			Some(ttl) => Some(expiry(now, ttl).ok_or(Rejections::TTLTooLong)?),
+			None => None,
+		};
 
-		while reg.get(&uuid_str).is_some() {
-			if has_id_req && reject {
-				return Err(Rejections::InUseID);
-			}
+		debug!("Registration has ttl {:?}", ttl);
 
-			log_vbs!(
-				vbs,
-				out,
-				"The uuid \x1b[1m{}\x1b[0m is already in use. Retrying...",
-				uuid_str
-			);
+		let destroy = Arc::new(RwLock::new(false));
 
-			let uuid = Uuid::new_v4().to_simple().to_string();
-			uuid_str = uuid[..8].to_owned();
-		}
+		// this id may already be taken, but that can only be checked while holding the
+		// registrations lock, in `create`
+		let uuid_str = uuid[..8].to_owned();
 
-		log!(
-			out,
-			Color::Green,
-			"Created new registration with uuid \x1b[1m{}\x1b[0m",
-			uuid_str
-		);
+		debug!("Created shortened uuid {}", uuid_str);
 
 		Ok(Registration {
 			uuid: uuid_str.to_owned(),
 			connections: Arc::new(RwLock::new(Vec::new())),
-			key,
-			host_key,
+			key: Arc::new(HashedKey::new(key)),
+			host_key: Arc::new(HashedKey::new(host_key)),
 			reg_type,
 			destroy,
+			expires_at,
+			last_active: Arc::new(RwLock::new(now)),
+			presence: req.presence.unwrap_or(false),
+			resume: req.resume.unwrap_or(false),
+			detached: Arc::new(RwLock::new(Vec::new())),
+			limits: ConnectionLimits {
+				max_connections: req.max_connections,
+				max_hosts: req.max_hosts,
+				max_clients: req.max_clients,
+				max_publishers: req.max_publishers,
+				max_subscribers: req.max_subscribers,
+			},
+			metadata: req.metadata,
+			public: req.public.unwrap_or(false),
+			host_auth: req.host_auth.unwrap_or(false),
+			locked: Arc::new(RwLock::new(false)),
+			keepalive,
+			message_limits,
+			rate_limits,
+			rate_limiter: SharedRateLimiter::default(),
+			used: false,
 		})
 	}
 
+	/// Handles `GET /register`, which responds with just the new registration's id
 	pub async fn new_handler(
 		body: RegisterRequest,
+		addr: Option<SocketAddr>,
 		rgs: Registrations,
 	) -> Result<impl Reply, Rejection> {
-		let (out, vbs) = Config::out_and_vbs().await;
+		Registration::create(body, addr, rgs).await
+	}
 
-		log!(
-			out,
-			Color::Green,
-			"Received request for new registration..."
-		);
+	/// Handles `POST /register`, which responds with `{"id": <the new registration's id>}`
+	pub async fn new_json_handler(
+		body: RegisterRequest,
+		addr: Option<SocketAddr>,
+		rgs: Registrations,
+	) -> Result<impl Reply, Rejection> {
+		let id = Registration::create(body, addr, rgs).await?;
+		Ok(warp::reply::json(&serde_json::json!({ "id": id })))
+	}
+
+	#[tracing::instrument(name = "register", skip_all, fields(reg_id = tracing::field::Empty))]
+	async fn create(
+		body: RegisterRequest,
+		addr: Option<SocketAddr>,
+		rgs: Registrations,
+	) -> Result<String, Rejection> {
+		info!("Received request for new registration...");
+
+		limits::check_ip(addr).await?;
 
 		let reg_type = match body.reg_type.as_str() {
 			"hostclient" => Some(RegistrationType::HostClient),
 			"lobby" => Some(RegistrationType::Lobby),
+			"broadcast" => Some(RegistrationType::Broadcast),
 			_ => None,
 		};
 
-		log_vbs!(vbs, out, "Registration has reg_type {:?}", reg_type);
+		debug!("Registration has reg_type {:?}", reg_type);
 
 		if let Some(reg) = reg_type {
-			let reg_clone = rgs.clone();
-			let new_register =
-				Registration::new(&body.key, &body.host_key, reg, body.id_req, reg_clone).await;
+			// a requested id has to be kept, or else the request is rejected
+			let keep_id = body.id_req.is_some() && CONFIG.read().await.reject_no_id;
+			let new_register = Registration::new(body, reg).await;
 
 			match new_register {
-				Ok(new_reg) => {
-					log_vbs!(vbs, out, "Successfully created new registration");
-
-					let uuid = new_reg.uuid.to_owned();
+				Ok(mut new_reg) => {
+					debug!("Successfully created new registration");
 
+					// the id is only checked and claimed under the write lock, so two requests
+					// for the same id can't both get it
 					let mut regs = rgs.write().await;
 
-					regs.insert(uuid.to_owned(), new_reg);
-					log!(
-						out,
-						Color::Green,
-						"Saved new registration with key \x1b[1m{}\x1b[0m",
-						uuid
-					);
-					Ok(uuid)
+					loop {
+						match regs.entry(new_reg.uuid.to_owned()) {
+							Entry::Vacant(entry) => {
+								let uuid = new_reg.uuid.to_owned();
+								tracing::Span::current()
+									.record("reg_id", tracing::field::display(&uuid));
+
+								new_reg.persist().await;
+								entry.insert(new_reg);
+
+								metrics::REGISTRATIONS_CREATED.inc();
+								info!("Saved new registration");
+								return Ok(uuid);
+							}
+							Entry::Occupied(_) if keep_id => {
+								warn!("Failed to make new registration: its id is in use");
+								return Err(metrics::reject(Rejections::InUseID));
+							}
+							Entry::Occupied(_) => {
+								debug!("The uuid {} is already in use. Retrying...", new_reg.uuid);
+
+								let uuid = Uuid::new_v4().to_simple().to_string();
+								new_reg.uuid = uuid[..8].to_owned();
+							}
+						}
+					}
 				}
 				Err(err) => {
-					err!(out, "Failed to make new registration: {}", err);
-					Err(reject::custom(err))
+					warn!("Failed to make new registration: {}", err);
+					Err(metrics::reject(err))
 				}
 			}
 		} else {
-			err!(out, "Registration type missing in registration request");
-			Err(reject::custom(Rejections::MissingRegistrationType))
+			warn!("Registration type missing in registration request");
+			Err(metrics::reject(Rejections::MissingRegistrationType))
 		}
 	}
 
-	pub async fn remove_handler(
-		body: RemoveRequest,
+	/// Handles `GET /registrations/{id}`, for anyone that can prove they have the registration's
+	/// key or host key with an `Authorization: Bearer <key>` header
+	#[tracing::instrument(name = "lookup", skip_all, fields(reg_id = %id))]
+	pub async fn lookup_handler(
+		id: String,
+		auth: Option<String>,
+		addr: Option<SocketAddr>,
 		rgs: Registrations,
 	) -> Result<impl Reply, Rejection> {
-		let (out, vbs) = Config::out_and_vbs().await;
+		info!("Received request to look up registration");
 
-		log!(
-			out,
-			Color::Yellow,
-			"Received request to remove registration with id \x1b[1m{}\x1b[0m",
-			body.id
-		);
+		limits::check_ip(addr).await?;
+
+		let (key, host_key) = match rgs.read().await.get(&id) {
+			Some(reg) => (reg.key.clone(), reg.host_key.clone()),
+			None => {
+				warn!("Registration not found");
+				return Err(metrics::not_found());
+			}
+		};
+
+		limits::check_id(&id).await?;
+
+		let provided = match bearer_token(&auth) {
+			Some(provided) => provided,
+			None => {
+				warn!("Rejecting because there's no bearer token");
+				return Err(metrics::reject(Rejections::InvalidKey));
+			}
+		};
+
+		if !(key.verify(provided).await || host_key.verify(provided).await) {
+			warn!("Failed to verify key. Not looking up registration");
+			limits::record_failure(&id).await;
+			return Err(metrics::reject(Rejections::InvalidKey));
+		}
+
+		limits::record_success(&id);
+
+		let regs = rgs.read().await;
+		let reg = regs.get(&id).ok_or_else(metrics::not_found)?;
+
+		Ok(warp::reply::json(&serde_json::json!({
+			"id": reg.uuid,
+			"reg_type": reg.reg_type,
+			"expires_at": reg.expires_at,
+			"presence": reg.presence,
+			"resume": reg.resume,
+			"public": reg.public,
+			"host_auth": reg.host_auth,
+			"locked": *reg.locked.read().await,
+			"limits": reg.limits,
+			"keepalive": reg.keepalive,
+			"message_limits": reg.message_limits,
+			"rate_limits": reg.rate_limits,
+			"counts": reg.connection_counts().await,
+			"metadata": reg.metadata
+		})))
+	}
+
+	/// Handles `POST /registrations/{id}/keys`, which replaces the registration's key and/or
+	/// host key for anyone with its current host key
+	#[tracing::instrument(name = "rotate", skip_all, fields(reg_id = %id))]
+	pub async fn rotate_handler(
+		id: String,
+		auth: Option<String>,
+		body: RotateRequest,
+		addr: Option<SocketAddr>,
+		rgs: Registrations,
+	) -> Result<impl Reply, Rejection> {
+		info!("Received request to rotate registration keys");
+
+		limits::check_ip(addr).await?;
+
+		let host_key = match rgs.read().await.get(&id) {
+			Some(reg) => reg.host_key.clone(),
+			None => {
+				warn!("Registration not found");
+				return Err(metrics::not_found());
+			}
+		};
+
+		limits::check_id(&id).await?;
+
+		let provided = match bearer_token(&auth) {
+			Some(provided) => provided,
+			None => {
+				warn!("Rejecting because there's no bearer token");
+				return Err(metrics::reject(Rejections::InvalidKey));
+			}
+		};
+
+		if !host_key.verify(provided).await {
+			warn!("Failed to verify host key. Not rotating keys");
+			limits::record_failure(&id).await;
+			return Err(metrics::reject(Rejections::InvalidKey));
+		}
+
+		limits::record_success(&id);
+
+		if body.key.is_none() && body.host_key.is_none() {
+			warn!("Rejecting because there are no new keys");
+			return Err(metrics::reject(Rejections::NoNewKeys));
+		}
+
+		let salt = CONFIG.read().await.secret_key.expose().as_bytes().to_vec();
+
+		let (new_key, new_host_key) = futures_util::future::join(
+			hash_new_key(body.key, salt.clone()),
+			hash_new_key(body.host_key, salt),
+		)
+		.await;
+		let (new_key, new_host_key) = (new_key?, new_host_key?);
+
+		let (key_rotated, host_key_rotated) = (new_key.is_some(), new_host_key.is_some());
 
 		let mut regs = rgs.write().await;
+		let reg = regs.get_mut(&id).ok_or_else(metrics::not_found)?;
 
-		let _ = if let Some(reg) = regs.get(&body.id) {
-			log_vbs!(vbs, out, "Verifying removal request keys...");
+		// someone else may have rotated the host key while we were hashing the new ones
+		if !Arc::ptr_eq(&reg.host_key, &host_key) {
+			warn!("Host key changed while rotating. Not rotating keys");
+			return Err(metrics::reject(Rejections::InvalidKey));
+		}
 
-			let key_ver = reg.verify_key(&body.key);
-			let host_ver = reg.verify_host_key(&body.host_key);
+		if let Some(new_key) = new_key {
+			reg.key = Arc::new(HashedKey::new(new_key));
+		}
 
-			if key_ver.await && host_ver.await {
-				log!(out, Color::Yellow, "Verified keys; removing registration");
+		if let Some(new_host_key) = new_host_key {
+			reg.host_key = Arc::new(HashedKey::new(new_host_key));
+		}
 
-				let mut destroy = reg.destroy.write().await;
-				*destroy = true;
-				drop(destroy);
+		reg.persist().await;
+
+		let mut disconnected = 0;
+
+		// every connection joined with the key, but only some of them with the host key
+		if body.disconnect.unwrap_or(false) {
+			for con in reg
+				.connections
+				.read()
+				.await
+				.iter()
+				.filter(|c| key_rotated || c.privileged)
+			{
+				con.queue.close(Some(Message::close_with(
+					close_codes::KEY_ROTATED,
+					"Registration keys changed",
+				)));
+				disconnected += 1;
+			}
 
-				Ok(())
-			} else {
-				err!(out, "Failed to verify keys. Not removing registration");
-				Err(reject::custom(Rejections::InvalidKey))
+			if key_rotated {
+				reg.detached.write().await.clear();
 			}
+		}
+
+		drop(regs);
+
+		info!(
+			target: "audit",
+			ip = ?addr.map(|a| a.ip()),
+			key_rotated,
+			host_key_rotated,
+			disconnected,
+			"Rotated registration keys"
+		);
+
+		Ok(warp::reply::json(&serde_json::json!({
+			"id": id,
+			"disconnected": disconnected
+		})))
+	}
+
+	#[tracing::instrument(name = "remove", skip_all, fields(reg_id = %body.id))]
+	pub async fn remove_handler(
+		body: RemoveRequest,
+		addr: Option<SocketAddr>,
+		rgs: Registrations,
+	) -> Result<impl Reply, Rejection> {
+		info!("Received request to remove registration");
+
+		limits::check_ip(addr).await?;
+
+		// verifying the keys takes a while, so don't keep everyone else waiting on the lock
+		// while it happens
+		let (key, host_key) = match rgs.read().await.get(&body.id) {
+			Some(reg) => (reg.key.clone(), reg.host_key.clone()),
+			None => {
+				warn!("Registration not found");
+				return Err(metrics::not_found());
+			}
+		};
+
+		limits::check_id(&body.id).await?;
+
+		debug!("Verifying removal request keys...");
+
+		let key_ver = key.verify(body.key.expose());
+		let host_ver = host_key.verify(body.host_key.expose());
+
+		if !(key_ver.await && host_ver.await) {
+			warn!("Failed to verify keys. Not removing registration");
+			limits::record_failure(&body.id).await;
+			return Err(metrics::reject(Rejections::InvalidKey));
+		}
+
+		limits::record_success(&body.id);
+
+		let mut regs = rgs.write().await;
+
+		if let Some(reg) = regs.get(&body.id) {
+			info!("Verified keys; removing registration");
+
+			let mut destroy = reg.destroy.write().await;
+			*destroy = true;
+			drop(destroy);
 		} else {
-			err!(out, "Registration not found");
-			Err(reject::not_found())
-		}?;
+			warn!("Registration not found");
+			return Err(metrics::not_found());
+		}
 
 		if let Entry::Occupied(reg) = regs.entry(body.id) {
-			reg.remove_entry();
+			let (uuid, _) = reg.remove_entry();
+			Registration::unpersist(&uuid).await;
+			metrics::REGISTRATIONS_REMOVED
+				.with_label_values(&["removed"])
+				.inc();
 			Ok("")
 		} else {
 			// This should be unreachable!(), since we already verified that it exists in
 			// the hashmap before getting here. However, we're not gonna panic 'cause this
 			// service needs to be, like, panic-proof
-			Err(reject::not_found())
+			Err(metrics::not_found())
+		}
+	}
+
+	/// Rebuilds a registration that was loaded from the store. It starts out with no
+	/// connections, since none of them could have survived the restart, and counts as active
+	/// as of now so that devices have a chance to reconnect before it's considered idle.
+	pub fn from_record(record: RegistrationRecord) -> Registration {
+		Registration {
+			uuid: record.uuid,
+			key: Arc::new(HashedKey::new(record.key)),
+			host_key: Arc::new(HashedKey::new(record.host_key)),
+			reg_type: record.reg_type,
+			connections: Arc::new(RwLock::new(Vec::new())),
+			destroy: Arc::new(RwLock::new(false)),
+			expires_at: record.expires_at,
+			last_active: Arc::new(RwLock::new(Utc::now().timestamp())),
+			presence: record.presence,
+			resume: record.resume,
+			detached: Arc::new(RwLock::new(Vec::new())),
+			limits: record.limits,
+			metadata: record.metadata,
+			public: record.public,
+			host_auth: record.host_auth,
+			locked: Arc::new(RwLock::new(false)),
+			keepalive: record.keepalive,
+			message_limits: record.message_limits,
+			rate_limits: record.rate_limits,
+			rate_limiter: SharedRateLimiter::default(),
+			used: record.used,
 		}
 	}
 
-	pub async fn verify_key(&self, key: &str) -> bool {
-		let (out, vbs) = Config::out_and_vbs().await;
+	pub fn record(&self) -> RegistrationRecord {
+		RegistrationRecord {
+			uuid: self.uuid.to_owned(),
+			key: self.key.hash.to_owned(),
+			host_key: self.host_key.hash.to_owned(),
+			reg_type: self.reg_type,
+			expires_at: self.expires_at,
+			presence: self.presence,
+			resume: self.resume,
+			limits: self.limits,
+			metadata: self.metadata.clone(),
+			public: self.public,
+			host_auth: self.host_auth,
+			keepalive: self.keepalive,
+			message_limits: self.message_limits,
+			rate_limits: self.rate_limits,
+			used: self.used,
+		}
+	}
 
-		log_vbs!(vbs, out, "Checking '{}' against '{}'", key, self.key);
+	/// A registration is expired if it's past its ttl, or if it has had no connections for
+	/// longer than `idle_ttl`. Without an `idle_ttl`, a registration that nobody has ever
+	/// connected to still expires once it's been around for longer than `unused_ttl`.
+	pub async fn is_expired(
+		&self,
+		now: i64,
+		idle_ttl: Option<u64>,
+		unused_ttl: Option<u64>,
+	) -> bool {
+		if matches!(self.expires_at, Some(exp) if exp <= now) {
+			return true;
+		}
 
-		argon2::verify_encoded(&self.key, key.as_bytes())
-			.unwrap_or_else(|_| {
-				err!(
-					out,
-					"Failed to verify key '{}' against hash '{}'",
-					key,
-					self.key
-				);
-				false
-			})
+		let idle_ttl = match idle_ttl {
+			Some(idle) => Some(idle),
+			None if !self.used => unused_ttl,
+			None => None,
+		};
+
+		match idle_ttl {
+			Some(idle) => {
+				self.connections.read().await.is_empty()
+					&& expiry(*self.last_active.read().await, idle).is_some_and(|exp| exp <= now)
+			}
+			None => false,
+		}
 	}
 
-	pub async fn verify_host_key(&self, key: &str) -> bool {
-		let (out, vbs) = Config::out_and_vbs().await;
+	/// Cleans up after a registration that was just taken out of the registrations map: marks it
+	/// as destroyed, closes every connection in it with `code` and `reason`, and removes it from
+	/// the store. `removed_because` is the label that it's counted under in the metrics.
+	pub async fn close(&self, code: u16, reason: &str, removed_because: &str) {
+		*self.destroy.write().await = true;
+		self.close_connections(code, reason).await;
+
+		Registration::unpersist(&self.uuid).await;
+		metrics::REGISTRATIONS_REMOVED
+			.with_label_values(&[removed_because])
+			.inc();
+	}
 
-		log_vbs!(vbs, out, "Checking '{}' against '{}", key, self.key);
+	/// Sends a close frame to every connection in this registration, after whatever is already
+	/// queued for them. The connections are actually removed by their `spawn_sending` tasks once
+	/// those notice that the socket closed.
+	pub async fn close_connections(&self, code: u16, reason: &str) {
+		let conns = self.connections.read().await;
 
-		argon2::verify_encoded(&self.host_key, key.as_bytes())
-			.unwrap_or_else(|_| {
-				err!(
-					out,
-					"Failed to verify host key '{}' against hash '{}'",
-					key,
-					self.host_key
-				);
-				false
-			})
+		for con in conns.iter() {
+			debug!(conn_id = %con.uuid, "Closing connection with code {}", code);
+
+			con.queue
+				.close(Some(Message::close_with(code, reason.to_owned())));
+		}
+	}
+
+	/// Queues this registration to be saved to the store. Failing to save it isn't fatal, since
+	/// the registration still works for as long as this process is running, so the writer just
+	/// logs it. Call this while still holding the registrations lock, so that the store sees
+	/// changes in the same order as the registrations map.
+	pub async fn persist(&self) {
+		STORE.read().await.insert(self.record());
+	}
+
+	pub async fn unpersist(uuid: &str) {
+		STORE.read().await.remove(uuid);
+	}
+
+	/// Counts the connections in this registration by their sock_type. Dropped connections that
+	/// can still be resumed are counted too, since they're still holding on to their place.
+	pub async fn connection_counts(&self) -> ConnectionCounts {
+		let grace = CONFIG.read().await.resume_grace;
+		let now = Utc::now().timestamp();
+
+		let mut counts = ConnectionCounts::default();
+
+		for con in self.connections.read().await.iter() {
+			counts.add(con.sock_type);
+		}
+
+		for d in self.detached.read().await.iter() {
+			if d.is_resumable(now, grace) {
+				counts.add(d.sock_type);
+			}
+		}
+
+		counts
+	}
+
+	/// Whether this registration has room for another connection of `sock_type`
+	pub async fn is_full(&self, sock_type: SocketType) -> bool {
+		self.limits
+			.is_full(&self.connection_counts().await, sock_type)
+	}
+
+	/// Checks whether `token` belongs to a dropped connection that can still be resumed
+	pub async fn can_resume(&self, token: &str) -> bool {
+		let grace = CONFIG.read().await.resume_grace;
+		let now = Utc::now().timestamp();
+
+		self.detached
+			.read()
+			.await
+			.iter()
+			.any(|d| d.resume_token == token && d.is_resumable(now, grace))
+	}
+
+	/// Removes and returns the dropped connection that `token` belongs to, if it can still be
+	/// resumed
+	pub async fn take_detached(&self, token: &str) -> Option<DetachedConnection> {
+		let grace = CONFIG.read().await.resume_grace;
+		let now = Utc::now().timestamp();
+
+		let mut detached = self.detached.write().await;
+		detached.retain(|d| d.is_resumable(now, grace));
+
+		let pos = detached.iter().position(|d| d.resume_token == token)?;
+		Some(detached.remove(pos))
 	}
 
 	pub async fn add_connection(
 		&mut self,
 		sender: SplitSink<WebSocket, Message>,
 		sock_type: SocketType,
+		resumed: Option<DetachedConnection>,
+		privileged: bool,
+		remote_addr: Option<SocketAddr>,
 	) -> String {
-		let (out, vbs) = Config::out_and_vbs().await;
+		debug!("Received request to add connection");
+
+		let is_resumed = resumed.is_some();
 
-		log_vbs!(vbs, out, "Received request to add connection");
+		let (uuid, buffered) = match resumed {
+			Some(detached) => {
+				debug!(
+					conn_id = %detached.uuid,
+					"Resuming connection with {} buffered message(s)",
+					detached.buffer.len()
+				);
+				(detached.uuid, detached.buffer)
+			}
+			None => {
+				let uuid = Uuid::new_v4().to_simple().to_string().to_lowercase();
+				debug!("Generated UUID of {}", uuid);
+				(uuid, VecDeque::new())
+			}
+		};
 
-		let uuid = Uuid::new_v4().to_simple().to_string().to_lowercase();
 		let uuid_clone = uuid.to_owned();
 
-		log_vbs!(vbs, out, "Generated UUID of \x1b[1m{}\x1b[0m", uuid);
+		let resume_token = if self.resume {
+			Some(Uuid::new_v4().to_simple().to_string())
+		} else {
+			None
+		};
+
+		*self.last_active.write().await = Utc::now().timestamp();
+
+		// it can't be reaped for going unused anymore, and that has to survive a restart
+		if !self.used {
+			self.used = true;
+			self.persist().await;
+		}
+
+		let conf = CONFIG.read().await;
+		let (queue_len, queue_policy) = (conf.queue_len, conf.queue_policy);
+		drop(conf);
 
 		let mut con = self.connections.write().await;
 
-		con.push(Connection {
+		let mut new_con = Connection::new(
 			sender,
 			sock_type,
 			uuid,
-		});
+			resume_token.clone(),
+			privileged,
+			queue_len,
+			queue_policy,
+		);
+		new_con.remote_addr = remote_addr;
+
+		con.push(new_con);
+
+		debug!("Inserted new connection");
+
+		// we're still holding the write lock, so nobody else can send anything to this
+		// connection until everything that was buffered for it has been replayed, in order
+		if let (Some(token), Some(new_con)) = (resume_token, con.last()) {
+			let session = ControlMessage::Session {
+				id: uuid_clone.to_owned(),
+				resume_token: token,
+				resumed: is_resumed,
+			};
+
+			if !new_con.send(session.to_message()) {
+				warn!(conn_id = %new_con.uuid, "Failed to queue session");
+			}
+
+			for msg in buffered {
+				if !new_con.send(msg) {
+					warn!(conn_id = %new_con.uuid, "Failed to queue replayed message");
+				}
+			}
+		}
 
-		log_vbs!(vbs, out, "Inserted new connection");
+		if self.presence {
+			let roster = con
+				.iter()
+				.filter(|c| c.uuid != uuid_clone)
+				.map(|c| Peer {
+					id: c.uuid.to_owned(),
+					sock_type: c.sock_type,
+				})
+				.collect();
+
+			let welcome = ControlMessage::Welcome {
+				id: uuid_clone.to_owned(),
+				sock_type,
+				roster,
+			};
+
+			let join = ControlMessage::Join {
+				id: uuid_clone.to_owned(),
+				sock_type,
+			};
+
+			for c in con.iter() {
+				let msg = if c.uuid == uuid_clone {
+					welcome.to_message()
+				} else {
+					join.to_message()
+				};
+
+				if !c.send(msg) {
+					warn!(conn_id = %c.uuid, "Failed to queue presence message");
+				}
+			}
+		}
 
 		uuid_clone
 	}
@@ -285,93 +812,335 @@ impl Registration {
 		registrations: Registrations,
 		con_uuid: String,
 		reg_uuid: String,
+		options: ConnectOptions,
 	) {
 		let conn = self.connections.clone();
 		let dest = self.destroy.clone();
-
-		tokio::spawn(async move {
+		let last_active = self.last_active.clone();
+		let presence = self.presence;
+		let resume = self.resume;
+		let detached = self.detached.clone();
+		let locked = self.locked.clone();
+		let keepalive = self.keepalive;
+		let message_limits = self.message_limits;
+		let rate_limits = self.rate_limits;
+		let shared_limiter = self.rate_limiter.clone();
+		// this is called from inside the connection's span, which already has its ids
+		let span = tracing::Span::current();
+
+		let task = async move {
 			let conf = CONFIG.read().await;
 			let auto_remove = conf.auto_remove;
+			let resume_grace = conf.resume_grace;
+			let settings = keepalive.settings(&conf);
+			let limits = message_limits.settings(&conf);
+			let mut rate_limiter = MessageRateLimiter::new(rate_limits.settings(&conf));
+			shared_limiter.init(rate_limits.total_settings(&conf));
 			drop(conf);
 
-			let (out, vbs) = Config::out_and_vbs().await;
+			let (bytes_received, rate_limited) =
+				match conn.read().await.iter().find(|c| c.uuid == con_uuid) {
+					Some(con) => (con.bytes_received.clone(), con.rate_limited.clone()),
+					None => (Arc::default(), Arc::default()),
+				};
+
+			let policy = rate_limiter.policy();
+
+			// whether the sender has already been told that it's over its own rate limits, or
+			// the ones that it shares with the rest of the registration, so that it isn't told
+			// again for every message until it slows down
+			let mut over_own_limit = false;
+			let mut over_shared_limit = false;
+
+			// whether this connection chose to leave, as opposed to just dropping
+			let mut closed = false;
+
+			// keepalive settings are validated to be small enough to add to now, but this task
+			// is the last place that should be able to panic over it
+			let first_ping = Instant::now()
+				.checked_add(settings.ping_interval)
+				.unwrap_or_else(Instant::now);
 
-			log!(
-				out,
-				Color::Yellow,
-				"Successfully upgraded. Awaiting messages..."
-			);
+			let mut ticker = tokio::time::interval_at(first_ping, settings.ping_interval);
+			ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+			// the ping that we're waiting on a pong for, and when it was sent
+			let mut pending: Option<(u64, Instant)> = None;
+			let mut ping_count: u64 = 0;
+			let mut missed = 0;
+
+			info!("Successfully upgraded. Awaiting messages...");
 
 			loop {
-				// try to get the next message. If there is none in 30 seconds, just send a ping
-				// so that the connection is maintained
-				if let Ok(next) = tokio::time::timeout(Duration::from_secs(30), receiver.next()).await
-				{
+				let deadline =
+					pending.and_then(|(_, sent)| sent.checked_add(settings.pong_timeout));
+
+				let next = tokio::select! {
+					next = receiver.next() => Some(next),
+					_ = ticker.tick() => None,
+					_ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now)),
+						if deadline.is_some() =>
+					{
+						pending = None;
+						missed += 1;
+						metrics::PING_TIMEOUTS.inc();
+						debug!("Missed pong ({} in a row)", missed);
+
+						// the connection is probably half-open, so it's treated like it dropped
+						if settings.max_missed_pongs > 0 && missed >= settings.max_missed_pongs {
+							warn!("Disconnecting after {} missed pongs in a row", missed);
+							metrics::DEAD_PEER_DISCONNECTS.inc();
+							break;
+						}
+
+						continue;
+					}
+				};
+
+				if let Some(next) = next {
 					let msg = match next {
 						Some(Ok(m)) => {
 							if m.is_pong() {
+								// pongs for pings that already timed out don't count
+								if let Some((id, sent)) = pending {
+									if m.as_bytes() == id.to_be_bytes() {
+										let rtt = sent.elapsed();
+										debug!("Got pong after {:?}", rtt);
+
+										pending = None;
+										missed = 0;
+
+										if let Some(con) =
+											conn.read().await.iter().find(|c| c.uuid == con_uuid)
+										{
+											con.rtt_micros.store(
+												rtt.as_micros().max(1) as u64,
+												Ordering::Relaxed,
+											);
+										}
+									}
+								}
 								continue;
 							}
+							// tungstenite already answers pings on its own, and they're only
+							// between this connection and the router, so they aren't forwarded
+							// or counted against any limits
+							if m.is_ping() {
+								continue;
+							}
+							// a close frame only ends this connection, so it mustn't be forwarded
+							// to the others like a normal message would be
+							if m.is_close() {
+								debug!("Connection sent a close frame, breaking...");
+								closed = true;
+								break;
+							}
 							m
 						}
 						Some(Err(err)) => {
-							err!(out, "Warp error when receiving next: {:?}", err);
+							if is_over_size_limit(&err) {
+								warn!("Closing because a message was too large: {}", err);
+								close_for_violation(
+									&conn,
+									&con_uuid,
+									close_codes::MESSAGE_TOO_BIG,
+									"Message too big",
+									"too_big",
+								)
+								.await;
+								break;
+							}
+
+							error!("Warp error when receiving next: {:?}", err);
 							continue;
 						}
 						_ => {
-							log_vbs!(
-								vbs,
-								out,
-								"Next message is none for connection {}, breaking...",
-								con_uuid
-							);
+							debug!("Next message is none, breaking...");
 							break;
 						}
 					};
 
+					bytes_received.fetch_add(msg.as_bytes().len() as u64, Ordering::Relaxed);
+
 					// check if this connection should be destroyed, break if so
 					if *dest.read().await {
-						log_vbs!(
-							vbs,
-							out,
-							"Should destroy connection {}, breaking...",
-							con_uuid
-						);
+						debug!("Should destroy connection, breaking...");
 						break;
 					}
 
-					let mut conns = conn.write().await;
+					let len = msg.as_bytes().len();
+
+					// this connection's own limits are checked before anything else, while the
+					// ones shared with the rest of the registration are only checked once it's
+					// certain that the message will be forwarded
+					match enforce_rate_limit(
+						|| rate_limiter.check(len),
+						policy,
+						&mut over_own_limit,
+						&conn,
+						&con_uuid,
+						&rate_limited,
+					)
+					.await
+					{
+						RateLimitOutcome::Allowed(waited) => {
+							// a pong that arrived while this was waiting wasn't late
+							pending = pending
+								.map(|(id, sent)| (id, sent.checked_add(waited).unwrap_or(sent)));
+						}
+						RateLimitOutcome::Drop => continue,
+						RateLimitOutcome::Disconnect => break,
+					}
+
+					// commands are for the router, not the other connections, so they're never
+					// forwarded
+					if options.control {
+						if let Some(cmd) = Command::parse(&msg) {
+							run_command(cmd, &con_uuid, &conn, &locked, &registrations, &reg_uuid)
+								.await;
+							continue;
+						}
+					}
+
+					if !limits.allows(&msg) {
+						warn!("Closing because a message was of a type that isn't allowed");
+						close_for_violation(
+							&conn,
+							&con_uuid,
+							close_codes::UNSUPPORTED_DATA,
+							"Message type not allowed",
+							"unsupported_data",
+						)
+						.await;
+						break;
+					}
+
+					// subscribers to a broadcast can only receive
+					let recv_type = match sock_type.recipients() {
+						Some(recv_type) => recv_type,
+						None => {
+							debug!("Dropping message from a connection that can't send any");
+							continue;
+						}
+					};
+
+					// only connections that opted in get their messages checked for an
+					// address, so that plain messages can never be mistaken for one
+					let env = match options.envelope {
+						true => match Envelope::open(&msg) {
+							Ok(env) => env,
+							Err(err) => {
+								// broadcasting it would send it to everyone, instead of the
+								// one connection that it was meant for
+								debug!("Dropping message: {}", err);
+								continue;
+							}
+						},
+						false => None,
+					};
+
+					match enforce_rate_limit(
+						|| shared_limiter.check(len),
+						policy,
+						&mut over_shared_limit,
+						&conn,
+						&con_uuid,
+						&rate_limited,
+					)
+					.await
+					{
+						RateLimitOutcome::Allowed(waited) => {
+							pending = pending
+								.map(|(id, sent)| (id, sent.checked_add(waited).unwrap_or(sent)));
+						}
+						RateLimitOutcome::Drop => continue,
+						RateLimitOutcome::Disconnect => break,
+					}
+
+					let conns = conn.read().await;
+
+					if let Some(env) = env {
+						let target = conns.iter().find(|c| {
+							c.uuid == env.to && c.sock_type == recv_type && c.uuid != con_uuid
+						});
+
+						if let Some(con) = target {
+							debug!(to = %con.uuid, "Attempting to send addressed message");
+
+							let sealed = env.seal(&con_uuid);
+							let len = sealed.as_bytes().len() as u64;
+
+							if con.send(sealed) {
+								metrics::MESSAGES_FORWARDED.inc();
+								metrics::BYTES_FORWARDED.inc_by(len);
+							} else {
+								warn!(to = %con.uuid, "Failed to queue message");
+							}
+						} else if resume
+							&& buffer_for_detached(&detached, &env.seal(&con_uuid), |d| {
+								d.uuid == env.to && d.sock_type == recv_type
+							})
+							.await > 0
+						{
+							debug!(to = %env.to, "Buffered message addressed to dropped connection");
+						} else {
+							debug!(to = %env.to, "Dropping addressed message, which can't be received");
+						}
+
+						continue;
+					}
+
+					// every connection that it's sent to shares this one copy of it while it's
+					// queued, so that a broadcast's slow subscribers don't each hold on to a
+					// backlog of copies. each one still copies it when it's written, though.
+					let shared = Arc::new(msg);
+					let mut forwarded = 0;
 
 					// find all the other connections that we should send this message to
-					for con in conns.iter_mut().filter(|c| {
-						c.sock_type
-							== match sock_type {
-								SocketType::Socket => SocketType::Socket,
-								SocketType::Client => SocketType::Host,
-								SocketType::Host => SocketType::Client,
-							} && c.uuid != con_uuid
-					}) {
-						// we have to clone it since we're sending it to multiple connections
-						let msg_clone = msg.clone();
-
-						log_vbs!(
-							vbs,
-							out,
-							"Attempting to send message to conn id {}",
-							con.uuid
-						);
-
-						if let Err(err) = con.sender.send(msg_clone).await {
-							err!(out, "Failed to send message: {:?}", err);
+					for con in conns
+						.iter()
+						.filter(|c| c.sock_type == recv_type && c.uuid != con_uuid)
+					{
+						trace!(to = %con.uuid, "Attempting to send message");
+
+						if con.send_shared(shared.clone()) {
+							forwarded += 1;
+						} else {
+							warn!(to = %con.uuid, "Failed to queue message");
 						}
 					}
+
+					debug!("Forwarded message to {} connection(s)", forwarded);
+					metrics::MESSAGES_FORWARDED.inc_by(forwarded);
+					metrics::BYTES_FORWARDED.inc_by(forwarded * len as u64);
+
+					drop(conns);
+
+					if resume && (shared.is_text() || shared.is_binary()) {
+						buffer_for_detached(&detached, &shared, |d| d.sock_type == recv_type).await;
+					}
 				} else {
-					// if the timeout doesn't return, just send a ping then poll again
-					let mut conns = conn.write().await;
+					// the registration may have been removed while this connection was quiet
+					if *dest.read().await {
+						debug!("Should destroy connection, breaking...");
+						break;
+					}
 
-					if let Some(con) = conns.iter_mut().find(|c| c.uuid == con_uuid) {
-						if let Err(err) = con.sender.send(Message::ping(vec![])).await {
-							err!(out, "Failed to send ping: {:?}", err);
+					// only one ping is ever waiting on a pong, so that every pong can be matched
+					// up with the ping it's answering
+					if pending.is_some() {
+						continue;
+					}
+
+					ping_count += 1;
+
+					let conns = conn.read().await;
+
+					if let Some(con) = conns.iter().find(|c| c.uuid == con_uuid) {
+						if con.send(Message::ping(ping_count.to_be_bytes().to_vec())) {
+							pending = Some((ping_count, Instant::now()));
+						} else {
+							warn!("Failed to queue ping");
 						}
 					}
 				}
@@ -380,59 +1149,380 @@ impl Registration {
 			let mut conns = conn.write().await;
 
 			if let Some(m_conn) = conns.iter().position(|c| c.uuid == con_uuid) {
-				let sink = conns.remove(m_conn);
-
-				if let Ok(ws) = receiver.reunite(sink.sender) {
-					match ws.close().await {
-						Err(err) => err!(out, "Failed to close websocket nicely: {}", err),
-						Ok(_) => log!(out, Color::Blue, "Successfully closed websocket nicely"),
+				let Connection {
+					queue,
+					resume_token,
+					..
+				} = conns.remove(m_conn);
+
+				// a connection that the server closed itself (e.g. because it was kicked)
+				// shouldn't get to come back by resuming
+				let kicked = queue.closed_by_server();
+
+				// the writer task closes the websocket once it's sent everything still queued
+				queue.close(None);
+				info!("Closing websocket");
+
+				// hold on to the session so that it can be resumed, unless the connection
+				// left on purpose or the whole registration is going away
+				if let Some(token) = resume_token {
+					if !closed && !kicked && !*dest.read().await {
+						debug!("Keeping session so that it can resume");
+
+						detached.write().await.push(DetachedConnection::new(
+							con_uuid.to_owned(),
+							sock_type,
+							token,
+							Utc::now().timestamp(),
+						));
 					}
-				} else {
-					err!(
-						out,
-						"Found matching sender but failed to reunite sender and receiver"
-					);
 				}
 			} else {
-				err!(out, "Failed to find matching connection to remove");
+				error!("Failed to find matching connection to remove");
+			}
+
+			if presence {
+				let leave = ControlMessage::Leave {
+					id: con_uuid.to_owned(),
+					sock_type,
+				}
+				.to_message();
+
+				for c in conns.iter() {
+					if !c.send(leave.clone()) {
+						warn!(conn_id = %c.uuid, "Failed to queue presence message");
+					}
+				}
 			}
 
 			let conns_len = conns.len();
 			drop(conns);
 
-			if conns_len == 0 && auto_remove {
-				log!(
-					out,
-					Color::Blue,
-					"No connections remaining. Removing registration..."
+			*last_active.write().await = Utc::now().timestamp();
+
+			// registrations have to outlive their connections while the server is shutting
+			// down, so that they can be saved and connected to again once it restarts
+			let auto_remove = auto_remove && !shutdown::is_shutting_down();
+
+			// give any dropped connections a chance to resume before removing the registration
+			// out from under them
+			if conns_len == 0 && auto_remove && !detached.read().await.is_empty() {
+				debug!(
+					"Waiting {}s for dropped connections to resume...",
+					resume_grace
 				);
 
+				tokio::time::sleep(Duration::from_secs(resume_grace)).await;
+
+				let now = Utc::now().timestamp();
+				let resumable = detached
+					.read()
+					.await
+					.iter()
+					.any(|d| d.is_resumable(now, resume_grace));
+
+				if resumable || !conn.read().await.is_empty() {
+					debug!("Connections resumed or are still resumable; not removing registration");
+					return;
+				}
+			}
+
+			if conns_len == 0 && auto_remove {
+				info!("No connections remaining. Removing registration...");
+
 				let mut regs = registrations.write().await;
 
 				if let Entry::Occupied(reg) = regs.entry(reg_uuid) {
-					reg.remove_entry();
+					let (uuid, _) = reg.remove_entry();
+					Registration::unpersist(&uuid).await;
+					metrics::REGISTRATIONS_REMOVED
+						.with_label_values(&["auto_removed"])
+						.inc();
 				}
 			} else if auto_remove {
-				log_vbs!(
-					vbs,
-					out,
+				debug!(
 					"Not removing registration. Remaining connections: {}",
 					conns_len
 				);
 			} else {
-				log!(
-					out,
-					Color::Blue,
-					"Remaining connections in this registration: {}",
-					conns_len
-				);
+				info!("Remaining connections in this registration: {}", conns_len);
+			}
+		};
+
+		tokio::spawn(task.instrument(span));
+	}
+}
+
+/// Returns the token from an `Authorization: Bearer <token>` header
+fn bearer_token(auth: &Option<String>) -> Option<&str> {
+	auth.as_deref().and_then(|a| a.strip_prefix("Bearer "))
+}
+
+/// The timestamp `secs` seconds after `from`, or `None` if that's too far in the future to
+/// represent
+fn expiry(from: i64, secs: u64) -> Option<i64> {
+	i64::try_from(secs)
+		.ok()
+		.and_then(|secs| from.checked_add(secs))
+}
+
+/// Hashes a key that's replacing one of a registration's keys, if there is one
+async fn hash_new_key(key: Option<Secret>, salt: Vec<u8>) -> Result<Option<String>, Rejection> {
+	match key {
+		Some(key) => hashing::hash(key.expose().to_owned(), salt)
+			.await
+			.map(Some)
+			.map_err(|err| {
+				error!("Failed to hash new key: {}", err);
+				metrics::reject(Rejections::UnhashableKey)
+			}),
+		None => Ok(None),
+	}
+}
+
+/// Carries out a command sent by a connection that joined with the host key
+async fn run_command(
+	cmd: Command,
+	con_uuid: &str,
+	conn: &RwLock<Vec<Connection>>,
+	locked: &RwLock<bool>,
+	registrations: &Registrations,
+	reg_uuid: &str,
+) {
+	info!("Running command {:?}", cmd);
+
+	match cmd {
+		Command::Kick { id } => {
+			let conns = conn.read().await;
+
+			match conns.iter().find(|c| c.uuid == id && c.uuid != con_uuid) {
+				Some(target) => target.queue.close(Some(Message::close_with(
+					close_codes::KICKED,
+					"Kicked by the host",
+				))),
+				None => {
+					let failed = ControlMessage::CommandFailed {
+						reason: format!("No connection with id {}", id),
+					}
+					.to_message();
+
+					if let Some(con) = conns.iter().find(|c| c.uuid == con_uuid) {
+						if !con.send(failed) {
+							warn!("Failed to queue command failure");
+						}
+					}
+				}
+			}
+		}
+		Command::Lock => *locked.write().await = true,
+		Command::Unlock => *locked.write().await = false,
+		Command::Close => {
+			let mut regs = registrations.write().await;
+
+			if let Some(reg) = regs.remove(reg_uuid) {
+				reg.close(
+					close_codes::CLOSED,
+					"Registration closed by the host",
+					"closed",
+				)
+				.await;
+			}
+		}
+	}
+}
+
+/// Closes a connection that broke its registration's message limits. It can't resume its
+/// session afterwards, since it was closed by the server.
+async fn close_for_violation(
+	conn: &RwLock<Vec<Connection>>,
+	con_uuid: &str,
+	code: u16,
+	reason: &'static str,
+	metric: &str,
+) {
+	metrics::MESSAGE_LIMIT_DISCONNECTS
+		.with_label_values(&[metric])
+		.inc();
+
+	if let Some(con) = conn.read().await.iter().find(|c| c.uuid == con_uuid) {
+		con.queue.close(Some(Message::close_with(code, reason)));
+	}
+}
+
+/// What to do with a message once it's been checked against a rate limiter
+enum RateLimitOutcome {
+	/// Forward it, after having waited this long for the limiter to allow it
+	Allowed(Duration),
+	Drop,
+	Disconnect,
+}
+
+/// Checks a message against a rate limiter with `check`, and deals with it the way `policy`
+/// says to if it's over the limits. The connection is told the first time that it is, and then
+/// not again until `check` allows one of its messages.
+async fn enforce_rate_limit(
+	mut check: impl FnMut() -> Result<(), Duration>,
+	policy: RateLimitPolicy,
+	notified: &mut bool,
+	conn: &RwLock<Vec<Connection>>,
+	con_uuid: &str,
+	rate_limited: &AtomicU64,
+) -> RateLimitOutcome {
+	let mut wait = match check() {
+		Ok(()) => {
+			*notified = false;
+			return RateLimitOutcome::Allowed(Duration::ZERO);
+		}
+		Err(wait) => wait,
+	};
+
+	rate_limited.fetch_add(1, Ordering::Relaxed);
+	metrics::RATE_LIMITED_MESSAGES
+		.with_label_values(&[policy.as_str()])
+		.inc();
+
+	if !*notified {
+		*notified = true;
+		debug!("Connection is over its rate limits; telling it to slow down");
+
+		let notice = ControlMessage::RateLimited {
+			action: policy,
+			retry_after_ms: u64::try_from(wait.as_millis()).unwrap_or(u64::MAX),
+		}
+		.to_message();
+
+		if let Some(con) = conn.read().await.iter().find(|c| c.uuid == con_uuid) {
+			con.send(notice);
+		}
+	}
+
+	match policy {
+		RateLimitPolicy::Drop => {
+			debug!("Dropping message that's over the rate limits");
+			RateLimitOutcome::Drop
+		}
+		RateLimitPolicy::Disconnect => {
+			warn!("Closing because the connection is over its rate limits");
+
+			if let Some(con) = conn.read().await.iter().find(|c| c.uuid == con_uuid) {
+				con.queue.close(Some(Message::close_with(
+					close_codes::RATE_LIMITED,
+					"Rate limit exceeded",
+				)));
+			}
+			RateLimitOutcome::Disconnect
+		}
+		RateLimitPolicy::Delay => {
+			// nothing else is read from the connection while it waits, so it's slowed down all
+			// the way back to its socket
+			let mut waited = Duration::ZERO;
+			loop {
+				debug!("Delaying message for {:?}", wait);
+				tokio::time::sleep(wait).await;
+				waited = waited.saturating_add(wait);
+
+				match check() {
+					Ok(()) => return RateLimitOutcome::Allowed(waited),
+					Err(next) => wait = next,
+				}
 			}
-		});
+		}
 	}
 }
 
-#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+/// Buffers a message for every dropped connection that matches `filter` and can still be
+/// resumed, returning how many connections it was buffered for
+async fn buffer_for_detached(
+	detached: &RwLock<Vec<DetachedConnection>>,
+	msg: &Message,
+	filter: impl Fn(&DetachedConnection) -> bool,
+) -> usize {
+	let conf = CONFIG.read().await;
+	let (grace, max_len, max_bytes) = (
+		conf.resume_grace,
+		conf.resume_buffer_len,
+		conf.resume_buffer_bytes,
+	);
+	drop(conf);
+
+	let now = Utc::now().timestamp();
+	let mut detached = detached.write().await;
+	detached.retain(|d| d.is_resumable(now, grace));
+
+	let mut count = 0;
+	for d in detached.iter_mut().filter(|d| filter(d)) {
+		d.buffer(msg.clone(), max_len, max_bytes);
+		count += 1;
+	}
+
+	count
+}
+
+#[derive(Eq, PartialEq, Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum RegistrationType {
 	HostClient,
 	Lobby,
+	Broadcast,
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn registration(expires_at: Option<i64>) -> Registration {
+		let mut record = RegistrationRecord::minimal("abcdefgh", RegistrationType::HostClient);
+		record.expires_at = expires_at;
+		Registration::from_record(record)
+	}
+
+	#[test]
+	fn expiry_rejects_ttls_that_overflow() {
+		assert_eq!(expiry(100, 50), Some(150));
+		assert_eq!(expiry(100, i64::MAX as u64), None);
+		assert_eq!(expiry(100, u64::MAX), None);
+		assert_eq!(
+			expiry(0, 9_223_372_036_854_775_000),
+			Some(9_223_372_036_854_775_000)
+		);
+		assert_eq!(expiry(1_700_000_000, 9_223_372_036_854_775_000), None);
+	}
+
+	#[tokio::test]
+	async fn expires_after_its_ttl() {
+		let now = Utc::now().timestamp();
+
+		assert!(!registration(None).is_expired(now, None, None).await);
+		assert!(
+			!registration(Some(now + 10))
+				.is_expired(now, None, None)
+				.await
+		);
+		assert!(registration(Some(now)).is_expired(now, None, None).await);
+	}
+
+	#[tokio::test]
+	async fn expires_once_idle_for_too_long() {
+		let reg = registration(None);
+		let now = Utc::now().timestamp();
+
+		assert!(!reg.is_expired(now, Some(60), None).await);
+		assert!(reg.is_expired(now + 60, Some(60), None).await);
+		assert!(!reg.is_expired(now, Some(u64::MAX), None).await);
+	}
+
+	#[tokio::test]
+	async fn expires_if_never_used() {
+		let mut reg = registration(None);
+		reg.used = false;
+		let now = Utc::now().timestamp();
+
+		assert!(!reg.is_expired(now, None, Some(60)).await);
+		assert!(reg.is_expired(now + 60, None, Some(60)).await);
+		// idle_ttl overrides it
+		assert!(!reg.is_expired(now + 60, Some(120), Some(60)).await);
+
+		reg.used = true;
+		assert!(!reg.is_expired(now + 60, None, Some(60)).await);
+	}
 }
@@ -1,4 +1,5 @@
 use thiserror::Error;
+use warp::http::StatusCode;
 
 #[derive(Debug, Error)]
 pub enum Rejections {
@@ -12,6 +13,58 @@ pub enum Rejections {
 	InUseID,
 	#[error("The ID must be exactly 8 characters long")]
 	IncorrectLengthID,
+	#[error("The requested ttl is longer than the server's max ttl and server is configured to reject invalid requests, or is too long to represent")]
+	TTLTooLong,
+	#[error("Too many requests; try again in {retry_after} seconds")]
+	TooManyRequests { retry_after: u64 },
+	#[error("Invalid metadata: {0}")]
+	InvalidMetadata(String),
+	#[error("Invalid keepalive: {0}")]
+	InvalidKeepalive(String),
+	#[error("Invalid message limits: {0}")]
+	InvalidMessageLimits(String),
+	#[error("Invalid rate limits: {0}")]
+	InvalidRateLimits(String),
+	#[error("Neither a new key nor a new host key was provided")]
+	NoNewKeys,
+}
+
+impl Rejections {
+	pub fn status(&self) -> StatusCode {
+		match self {
+			Rejections::MissingRegistrationType
+			| Rejections::UnhashableKey
+			| Rejections::IncorrectLengthID
+			| Rejections::TTLTooLong
+			| Rejections::InvalidMetadata(_)
+			| Rejections::InvalidKeepalive(_)
+			| Rejections::InvalidMessageLimits(_)
+			| Rejections::InvalidRateLimits(_)
+			| Rejections::NoNewKeys => StatusCode::BAD_REQUEST,
+			Rejections::InvalidKey => StatusCode::UNAUTHORIZED,
+			Rejections::InUseID => StatusCode::CONFLICT,
+			Rejections::TooManyRequests { .. } => StatusCode::TOO_MANY_REQUESTS,
+		}
+	}
+
+	/// The code that clients get in the body of the response. These should never change, since
+	/// clients match on them.
+	pub fn code(&self) -> &'static str {
+		match self {
+			Rejections::MissingRegistrationType => "missing_registration_type",
+			Rejections::UnhashableKey => "unhashable_key",
+			Rejections::InvalidKey => "invalid_key",
+			Rejections::InUseID => "id_in_use",
+			Rejections::IncorrectLengthID => "invalid_id_length",
+			Rejections::TTLTooLong => "ttl_too_long",
+			Rejections::TooManyRequests { .. } => "too_many_requests",
+			Rejections::InvalidMetadata(_) => "invalid_metadata",
+			Rejections::InvalidKeepalive(_) => "invalid_keepalive",
+			Rejections::InvalidMessageLimits(_) => "invalid_message_limits",
+			Rejections::InvalidRateLimits(_) => "invalid_rate_limits",
+			Rejections::NoNewKeys => "no_new_keys",
+		}
+	}
 }
 
 impl warp::reject::Reject for Rejections {}
@@ -1,8 +1,9 @@
+use crate::secret::Secret;
 use serde::Deserialize;
 
 #[derive(Deserialize)]
 pub struct RemoveRequest {
 	pub id: String,
-	pub key: String,
-	pub host_key: String,
+	pub key: Secret,
+	pub host_key: Secret,
 }
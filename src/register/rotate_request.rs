@@ -0,0 +1,11 @@
+use crate::secret::Secret;
+use serde::Deserialize;
+
+/// New keys for a registration. Whichever key is left out stays the same.
+#[derive(Deserialize)]
+pub struct RotateRequest {
+	pub key: Option<Secret>,
+	pub host_key: Option<Secret>,
+	/// Whether to disconnect the connections that joined with a key that's being replaced
+	pub disconnect: Option<bool>,
+}
@@ -0,0 +1,44 @@
+use serde::Deserialize;
+use std::fmt;
+
+/// A key, or anything else that should never show up in logs. Its `Debug` and `Display`
+/// implementations never print what it contains, so it can't end up in a log by accident; the
+/// only way to get at the contents is to call `expose`.
+#[derive(Deserialize, Clone, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+	pub fn new(secret: String) -> Secret {
+		Secret(secret)
+	}
+
+	pub fn expose(&self) -> &str {
+		&self.0
+	}
+}
+
+impl fmt::Debug for Secret {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str("[redacted]")
+	}
+}
+
+impl fmt::Display for Secret {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str("[redacted]")
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn never_formats_its_contents() {
+		let secret: Secret = serde_json::from_str(r#""hunter2""#).unwrap();
+
+		assert_eq!(secret.expose(), "hunter2");
+		assert_eq!(format!("{} {:?}", secret, secret), "[redacted] [redacted]");
+	}
+}
@@ -0,0 +1,158 @@
+use crate::{
+	metrics,
+	sockets::close_codes,
+	store::{FileStore, RegistrationRecord},
+	Registrations, CONFIG, STORE,
+};
+use lazy_static::lazy_static;
+use std::{
+	sync::atomic::{AtomicBool, Ordering},
+	time::Duration,
+};
+use thiserror::Error;
+use tokio::{sync::Notify, time::Instant};
+use tracing::{error, info, warn};
+use warp::{Filter, Rejection};
+
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+	static ref SHUTDOWN: Notify = Notify::new();
+}
+
+#[derive(Debug, Error)]
+#[error("The server is shutting down")]
+pub struct ShuttingDown;
+
+impl warp::reject::Reject for ShuttingDown {}
+
+pub fn is_shutting_down() -> bool {
+	SHUTTING_DOWN.load(Ordering::Relaxed)
+}
+
+/// Rejects every request once the server has started shutting down. This goes in front of the
+/// routes that would give us something new to drain, like registrations and connections.
+pub fn accepting() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+	warp::any()
+		.and_then(|| async {
+			if is_shutting_down() {
+				Err(metrics::reject(ShuttingDown))
+			} else {
+				Ok(())
+			}
+		})
+		.untuple_one()
+}
+
+/// Resolves once the server has started shutting down. Every listener waits on this, so that
+/// they all stop accepting connections at once.
+pub async fn triggered() {
+	loop {
+		// this has to exist before checking the flag, or we could miss the notification
+		let notified = SHUTDOWN.notified();
+
+		if is_shutting_down() {
+			return;
+		}
+
+		notified.await;
+	}
+}
+
+/// Waits for the server to be told to stop, with either SIGTERM or ctrl-c, and then starts
+/// shutting it down
+pub async fn listen_for_signal() {
+	#[cfg(unix)]
+	let terminate = async {
+		match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+			Ok(mut term) => {
+				term.recv().await;
+			}
+			Err(err) => {
+				error!("Failed to listen for SIGTERM: {}", err);
+				std::future::pending::<()>().await
+			}
+		}
+	};
+
+	#[cfg(not(unix))]
+	let terminate = std::future::pending::<()>();
+
+	tokio::select! {
+		_ = tokio::signal::ctrl_c() => {}
+		_ = terminate => {}
+	}
+
+	info!("Received signal to shut down. No longer accepting new registrations or connections");
+	SHUTTING_DOWN.store(true, Ordering::Relaxed);
+	SHUTDOWN.notify_waiters();
+}
+
+/// Sends every connection a close frame, waits up to `drain_timeout` seconds for them to
+/// finish closing, and then saves every registration to `shutdown_snapshot`, if it's set. It
+/// also waits for the store to finish writing any changes that are still queued.
+pub async fn drain(rgs: Registrations) {
+	let conf = CONFIG.read().await;
+	let timeout = Duration::from_secs(conf.drain_timeout);
+	let reason = conf.shutdown_reason.clone().unwrap_or_default();
+	let snapshot = conf.shutdown_snapshot.clone();
+	drop(conf);
+
+	let regs = rgs.read().await;
+
+	info!("Closing connections to {} registration(s)...", regs.len());
+
+	for reg in regs.values() {
+		reg.close_connections(close_codes::GOING_AWAY, &reason)
+			.await;
+	}
+
+	drop(regs);
+
+	// a timeout too long to add to now is the same as having no timeout at all
+	let deadline = Instant::now().checked_add(timeout);
+
+	loop {
+		let mut remaining = 0;
+
+		for reg in rgs.read().await.values() {
+			remaining += reg.connections.read().await.len();
+		}
+
+		if remaining == 0 {
+			info!("Every connection has closed");
+			break;
+		}
+
+		if matches!(deadline, Some(deadline) if Instant::now() >= deadline) {
+			warn!(
+				"Giving up on {} connection(s) that didn't close within {}s",
+				remaining,
+				timeout.as_secs()
+			);
+			break;
+		}
+
+		tokio::time::sleep(Duration::from_millis(100)).await;
+	}
+
+	if let Some(path) = snapshot {
+		let records: Vec<RegistrationRecord> =
+			rgs.read().await.values().map(|reg| reg.record()).collect();
+
+		let count = records.len();
+
+		let written = {
+			let path = path.clone();
+			tokio::task::spawn_blocking(move || FileStore::write_snapshot(&path, records)).await
+		};
+
+		match written {
+			Ok(Ok(_)) => info!("Saved {} registration(s) to '{}'", count, path),
+			Ok(Err(err)) => error!("Failed to save registrations to '{}': {}", path, err),
+			Err(err) => error!("Failed to save registrations to '{}': {}", path, err),
+		}
+	}
+
+	STORE.read().await.sync().await;
+}
@@ -0,0 +1,41 @@
+//! Close codes that the router sends when it closes a websocket on its own. Codes from 4000 up
+//! are reserved for applications by RFC 6455, so the router-specific ones live there.
+
+/// The server is shutting down. This is the standard "going away" code from RFC 6455, since
+/// it isn't specific to the router.
+pub const GOING_AWAY: u16 = 1001;
+
+/// The connection sent a kind of message (text or binary) that its registration doesn't accept.
+/// This is the standard "unsupported data" code from RFC 6455.
+pub const UNSUPPORTED_DATA: u16 = 1003;
+
+/// The connection sent a message or frame that's larger than its registration allows. This is
+/// the standard "message too big" code from RFC 6455.
+pub const MESSAGE_TOO_BIG: u16 = 1009;
+
+/// The registration this connection belonged to expired or sat idle for too long
+pub const EXPIRED: u16 = 4000;
+
+/// This connection couldn't keep up with the messages being sent to it, and the server is
+/// configured to disconnect connections like that
+pub const SLOW_CONSUMER: u16 = 4001;
+
+/// The registration filled up while this connection was being upgraded
+pub const FULL: u16 = 4002;
+
+/// A host kicked this connection out of the registration
+pub const KICKED: u16 = 4003;
+
+/// A host closed the registration that this connection belonged to
+pub const CLOSED: u16 = 4004;
+
+/// The key that this connection joined with was replaced, and whoever replaced it asked for
+/// everyone using the old one to be disconnected
+pub const KEY_ROTATED: u16 = 4005;
+
+/// An administrator removed the registration that this connection belonged to
+pub const REMOVED: u16 = 4006;
+
+/// This connection sent messages faster than its rate limits allow, and its registration is
+/// configured to disconnect connections like that
+pub const RATE_LIMITED: u16 = 4007;
@@ -0,0 +1,60 @@
+use serde::Deserialize;
+use warp::ws::Message;
+
+/// Something that a connection which joined with the registration's host key and `control`
+/// can tell the router to do, by sending a text message containing a JSON object with a
+/// `command` field
+#[derive(Deserialize, Debug)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Command {
+	/// Disconnect another connection, without letting it resume its session
+	Kick { id: String },
+	/// Stop anyone without the host key from joining
+	Lock,
+	/// Let anyone with the key join again
+	Unlock,
+	/// Remove the registration, disconnecting everyone in it
+	Close,
+}
+
+impl Command {
+	/// Returns the command that `msg` contains, or `None` if it isn't one
+	pub fn parse(msg: &Message) -> Option<Command> {
+		msg.to_str()
+			.ok()
+			.and_then(|text| serde_json::from_str(text).ok())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parses_commands() {
+		assert!(matches!(
+			Command::parse(&Message::text(r#"{"command": "kick", "id": "abc"}"#)),
+			Some(Command::Kick { id }) if id == "abc"
+		));
+		assert!(matches!(
+			Command::parse(&Message::text(r#"{"command": "lock"}"#)),
+			Some(Command::Lock)
+		));
+		assert!(matches!(
+			Command::parse(&Message::text(r#"{"command": "close"}"#)),
+			Some(Command::Close)
+		));
+	}
+
+	#[test]
+	fn anything_else_is_a_normal_message() {
+		for msg in [
+			Message::text("lock"),
+			Message::text(r#"{"command": "explode"}"#),
+			Message::text(r#"{"command": "kick"}"#),
+			Message::binary(br#"{"command": "lock"}"#.to_vec()),
+		] {
+			assert!(Command::parse(&msg).is_none());
+		}
+	}
+}
@@ -0,0 +1,83 @@
+use crate::{register::RateLimitPolicy, sockets::SocketType};
+use serde::Serialize;
+use warp::ws::Message;
+
+/// A message that the router itself sends to a connection, as opposed to one that it's
+/// forwarding from another connection. These are always text messages containing a JSON object
+/// with an `event` field that says what kind of message it is.
+#[derive(Serialize, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ControlMessage {
+	/// Sent to a connection as soon as it joins, so that it knows its own id and who else is
+	/// already connected
+	Welcome {
+		id: String,
+		sock_type: SocketType,
+		roster: Vec<Peer>,
+	},
+	/// Sent to a connection as soon as it joins a registration that allows resuming, with the
+	/// token it can use to resume this session if it drops. `resumed` is true if this
+	/// connection just resumed an earlier session, in which case any messages that were
+	/// buffered for it are sent right after this.
+	Session {
+		id: String,
+		resume_token: String,
+		resumed: bool,
+	},
+	/// Another connection joined the registration
+	Join { id: String, sock_type: SocketType },
+	/// Another connection left the registration
+	Leave { id: String, sock_type: SocketType },
+	/// A command that this connection sent couldn't be carried out
+	CommandFailed { reason: String },
+	/// A message from the server's administrators to everyone in the registration
+	Notice { message: String },
+	/// This connection is sending messages faster than its rate limits allow. `action` is what
+	/// happens to the messages that are over the limit, and `retry_after_ms` is how long it'll
+	/// be until the next one is allowed.
+	RateLimited {
+		action: RateLimitPolicy,
+		retry_after_ms: u64,
+	},
+}
+
+#[derive(Serialize, Debug)]
+pub struct Peer {
+	pub id: String,
+	pub sock_type: SocketType,
+}
+
+impl ControlMessage {
+	pub fn to_message(&self) -> Message {
+		// serializing can't fail, since every field is either a String, a bool, or a unit enum
+		Message::text(serde_json::to_string(self).unwrap_or_default())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn json(msg: ControlMessage) -> serde_json::Value {
+		serde_json::from_str(msg.to_message().to_str().unwrap()).unwrap()
+	}
+
+	#[test]
+	fn tagged_by_event() {
+		assert_eq!(
+			json(ControlMessage::Join {
+				id: "abc".to_owned(),
+				sock_type: SocketType::Client,
+			}),
+			serde_json::json!({"event": "join", "id": "abc", "sock_type": "client"})
+		);
+
+		assert_eq!(
+			json(ControlMessage::RateLimited {
+				action: RateLimitPolicy::Delay,
+				retry_after_ms: 250,
+			}),
+			serde_json::json!({"event": "rate_limited", "action": "delay", "retry_after_ms": 250})
+		);
+	}
+}
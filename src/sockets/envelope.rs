@@ -0,0 +1,164 @@
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use thiserror::Error;
+use warp::ws::Message;
+
+/// Connection uuids are always a simple (hyphenless) v4 uuid, so they're always this long
+pub const UUID_LEN: usize = 32;
+
+#[derive(Deserialize)]
+struct TextEnvelope {
+	to: String,
+	#[serde(default)]
+	data: Value,
+}
+
+/// A text message that has a `to`, but isn't a valid envelope otherwise. It's still meant for
+/// only one connection, so it mustn't be broadcast like a plain message would be.
+#[derive(Debug, Error)]
+#[error("The message has a 'to', but isn't a valid envelope: {0}")]
+pub struct InvalidEnvelope(serde_json::Error);
+
+/// A message that a connection wants delivered to exactly one other connection, instead of
+/// being broadcast. Text messages are addressed with a JSON object of the form
+/// `{"to": "<uuid>", "data": "<message>"}`, and binary messages are addressed by prefixing
+/// the payload with the 32 ascii bytes of the destination uuid.
+pub struct Envelope {
+	pub to: String,
+	data: Payload,
+}
+
+enum Payload {
+	Text(Value),
+	Binary(Vec<u8>),
+}
+
+impl Envelope {
+	/// Tries to read an envelope out of a message. Returns `None` for anything that isn't
+	/// addressed, so that it can just be broadcast like normal, and an error for a text message
+	/// that's a JSON object with a `to` but can't be read as an envelope.
+	pub fn open(msg: &Message) -> Result<Option<Envelope>, InvalidEnvelope> {
+		if msg.is_text() {
+			let object = match msg.to_str().map(serde_json::from_str::<Map<String, Value>>) {
+				Ok(Ok(object)) if object.contains_key("to") => object,
+				_ => return Ok(None),
+			};
+
+			let env: TextEnvelope =
+				serde_json::from_value(Value::Object(object)).map_err(InvalidEnvelope)?;
+
+			Ok(Some(Envelope {
+				to: env.to,
+				data: Payload::Text(env.data),
+			}))
+		} else if msg.is_binary() {
+			let bytes = msg.as_bytes();
+
+			if bytes.len() < UUID_LEN {
+				return Ok(None);
+			}
+
+			let to = match std::str::from_utf8(&bytes[..UUID_LEN]) {
+				Ok(to) if to.bytes().all(|b| b.is_ascii_hexdigit()) => to,
+				_ => return Ok(None),
+			};
+
+			Ok(Some(Envelope {
+				to: to.to_owned(),
+				data: Payload::Binary(bytes[UUID_LEN..].to_vec()),
+			}))
+		} else {
+			Ok(None)
+		}
+	}
+
+	/// Builds the message that the destination actually receives, which carries the uuid of
+	/// the connection that sent it in place of the destination, so that it can reply.
+	pub fn seal(&self, from: &str) -> Message {
+		match &self.data {
+			Payload::Text(data) => Message::text(
+				serde_json::json!({
+					"from": from,
+					"data": data
+				})
+				.to_string(),
+			),
+			Payload::Binary(data) => {
+				let mut bytes = Vec::with_capacity(UUID_LEN + data.len());
+				bytes.extend_from_slice(from.as_bytes());
+				bytes.extend_from_slice(data);
+				Message::binary(bytes)
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const TO: &str = "0123456789abcdef0123456789abcdef";
+	const FROM: &str = "fedcba9876543210fedcba9876543210";
+
+	fn seal_text(msg: &str) -> Value {
+		let env = Envelope::open(&Message::text(msg)).unwrap().unwrap();
+		assert_eq!(env.to, TO);
+
+		serde_json::from_str(env.seal(FROM).to_str().unwrap()).unwrap()
+	}
+
+	#[test]
+	fn opens_text_envelopes() {
+		assert_eq!(
+			seal_text(&format!(r#"{{"to": "{}", "data": "hi"}}"#, TO)),
+			serde_json::json!({"from": FROM, "data": "hi"})
+		);
+	}
+
+	#[test]
+	fn text_envelopes_can_carry_any_json() {
+		assert_eq!(
+			seal_text(&format!(r#"{{"to": "{}", "data": {{"x": [1, 2]}}}}"#, TO)),
+			serde_json::json!({"from": FROM, "data": {"x": [1, 2]}})
+		);
+		assert_eq!(
+			seal_text(&format!(r#"{{"to": "{}"}}"#, TO)),
+			serde_json::json!({"from": FROM, "data": null})
+		);
+	}
+
+	#[test]
+	fn rejects_addressed_messages_that_arent_envelopes() {
+		for msg in [r#"{"to": 5, "data": "hi"}"#, r#"{"to": null}"#] {
+			assert!(Envelope::open(&Message::text(msg)).is_err());
+		}
+	}
+
+	#[test]
+	fn opens_binary_envelopes() {
+		let mut bytes = TO.as_bytes().to_vec();
+		bytes.extend_from_slice(&[1, 2, 3]);
+
+		let env = Envelope::open(&Message::binary(bytes)).unwrap().unwrap();
+		assert_eq!(env.to, TO);
+
+		let sealed = env.seal(FROM);
+		assert!(sealed.is_binary());
+		assert_eq!(&sealed.as_bytes()[..UUID_LEN], FROM.as_bytes());
+		assert_eq!(&sealed.as_bytes()[UUID_LEN..], &[1, 2, 3]);
+	}
+
+	#[test]
+	fn ignores_messages_that_arent_addressed() {
+		for msg in [
+			Message::text("hello"),
+			Message::text(r#"["to"]"#),
+			Message::text(r#"{"data": "no recipient"}"#),
+			Message::binary(vec![0; UUID_LEN - 1]),
+			Message::binary(b"not a uuid, but thirty-two bytes".to_vec()),
+			Message::ping(Vec::new()),
+		] {
+			assert!(matches!(Envelope::open(&msg), Ok(None)));
+		}
+	}
+}
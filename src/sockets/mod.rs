@@ -1,7 +1,14 @@
+pub use command::*;
+pub use control::*;
+pub use envelope::*;
 pub use rejections::*;
 pub use socket::*;
 pub use socket_request::*;
 
+pub mod close_codes;
+mod command;
+mod control;
+mod envelope;
 mod rejections;
 mod socket;
 mod socket_request;
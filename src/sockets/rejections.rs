@@ -1,7 +1,47 @@
-#[derive(Debug)]
+use thiserror::Error;
+use warp::http::StatusCode;
+
+#[derive(Debug, Error)]
 pub enum Rejections {
+	#[error("Provided key is invalid")]
 	IncorrectKey,
+	#[error("The sock_type must be either 'host' or 'client' for hostclient registrations, or 'publisher' or 'subscriber' for broadcast registrations")]
 	InvalidSockType,
+	#[error("The resume token is invalid or has expired")]
+	InvalidResumeToken,
+	#[error("The registration already has as many connections of this type as it allows")]
+	RegistrationFull,
+	#[error("This requires the registration's host key")]
+	HostKeyRequired,
+	#[error("The registration is locked, and isn't accepting new connections")]
+	RegistrationLocked,
+}
+
+impl Rejections {
+	pub fn status(&self) -> StatusCode {
+		match self {
+			Rejections::IncorrectKey | Rejections::InvalidResumeToken => StatusCode::UNAUTHORIZED,
+			Rejections::InvalidSockType => StatusCode::BAD_REQUEST,
+			Rejections::RegistrationFull => StatusCode::CONFLICT,
+			Rejections::HostKeyRequired => StatusCode::UNAUTHORIZED,
+			Rejections::RegistrationLocked => StatusCode::LOCKED,
+		}
+	}
+
+	/// The code that clients get in the body of the response. These should never change, since
+	/// clients match on them.
+	pub fn code(&self) -> &'static str {
+		match self {
+			// this is the same mistake as `register::Rejections::InvalidKey`, so clients
+			// shouldn't have to check for it twice
+			Rejections::IncorrectKey => "invalid_key",
+			Rejections::InvalidSockType => "invalid_sock_type",
+			Rejections::InvalidResumeToken => "invalid_resume_token",
+			Rejections::RegistrationFull => "registration_full",
+			Rejections::HostKeyRequired => "host_key_required",
+			Rejections::RegistrationLocked => "registration_locked",
+		}
+	}
 }
 
 impl warp::reject::Reject for Rejections {}
@@ -1,135 +1,316 @@
-use crate::{config::*, err, log, log_vbs, register::RegistrationType, sockets::*, Registrations};
-use futures_util::StreamExt;
-use warp::{reject, ws::WebSocket, Rejection, Reply};
+use crate::{limits, metrics, register::RegistrationType, sockets::*, Registrations, CONFIG};
+use futures_util::{SinkExt, StreamExt};
+use serde::Serialize;
+use std::{net::SocketAddr, sync::Arc};
+use tracing::{debug, info, warn};
+use warp::{
+	ws::{Message, WebSocket},
+	Rejection, Reply,
+};
 
 pub struct Socket;
 
 impl Socket {
+	#[tracing::instrument(name = "connect", skip_all, fields(reg_id = %req.id))]
 	pub async fn connect_handler(
 		ws: warp::ws::Ws,
 		req: SocketRequest,
+		addr: Option<SocketAddr>,
 		registrations: Registrations,
 	) -> Result<impl Reply, Rejection> {
-		let (out, _) = Config::out_and_vbs().await;
+		info!("Websocket attempting to connect to registration");
 
-		log!(
-			out,
-			Color::Blue,
-			"Websocket attempting to connect to registration with id \x1b[1m{}\x1b[0m",
-			req.id
-		);
+		limits::check_ip(addr).await?;
+
+		let (key, host_key) = match registrations.read().await.get(&req.id) {
+			Some(reg) => (reg.key.clone(), reg.host_key.clone()),
+			None => {
+				warn!("Request attempted to access a registration that does not exist");
+				return Err(metrics::not_found());
+			}
+		};
+
+		limits::check_id(&req.id).await?;
+
+		// verifying the key takes a while, so don't keep everyone else waiting on the lock while
+		// it happens
+		if !key.verify(req.key.expose()).await {
+			warn!("Rejecting because the key is incorrect");
+			limits::record_failure(&req.id).await;
+			return Err(metrics::reject(Rejections::IncorrectKey));
+		}
+
+		// most connections don't need the host key, so it's only checked if it was given
+		let has_host_key = match req.host_key {
+			Some(ref req_host_key) => {
+				if !host_key.verify(req_host_key.expose()).await {
+					warn!("Rejecting because the host key is incorrect");
+					limits::record_failure(&req.id).await;
+					return Err(metrics::reject(Rejections::IncorrectKey));
+				}
+				true
+			}
+			None => false,
+		};
+
+		limits::record_success(&req.id);
+
+		info!("Key verified successfully");
 
 		let regists = registrations.read().await;
 
-		let reg_type = if let Some(reg) = regists.get(&req.id) {
-			if !reg.verify_key(&req.key).await {
-				err!(
-					out,
-					"Failed to verify key ('{}' against hash '{}')",
-					req.key,
-					reg.key
+		let reg_type = match regists.get(&req.id) {
+			Some(reg) => reg.reg_type,
+			None => {
+				warn!("Request attempted to access a registration that does not exist");
+				return Err(metrics::not_found());
+			}
+		};
+
+		info!("Got reg_type {:?}", reg_type);
+
+		let sock_type = match SocketType::joining(reg_type, req.sock_type.as_deref()) {
+			Some(sock_type) => sock_type,
+			None => {
+				warn!(
+					"Rejecting because sock_type is '{:?}', which is not allowed",
+					req.sock_type
 				);
-				Err(reject::custom(Rejections::IncorrectKey))
-			} else {
-				log!(out, Color::Blue, "Key verified successfully");
-
-				match reg.reg_type {
-					RegistrationType::HostClient => {
-						req.sock_type.as_ref()
-							.map_or_else(|| {
-								err!(out, "Rejecting because req.sock_type is none");
-								Err(reject::custom(Rejections::InvalidSockType))
-							}, |st| {
-								// remove potential trailing slashes 'cause that's what the
-								// rust URL crate adds
-								let st_rem = st.as_str().replace("/", "");
-
-								if st_rem != "host" && st_rem != "client" {
-									err!(
-										out,
-										"Rejecting because st is '{}', which is not allowed",
-										st
-									);
-									Err(reject::custom(Rejections::InvalidSockType))
-								} else {
-									Ok(reg.reg_type)
-								}
-							})
-					}
-					_ => Ok(reg.reg_type),
-				}
+				return Err(metrics::reject(Rejections::InvalidSockType));
 			}
-		} else {
-			err!(
-				out,
-				"Request attempted to access registration with id {}, which does not exist",
-				req.id
-			);
-			Err(reject::not_found())
-		}?;
-
-		log!(out, Color::Blue, "Got reg_type {:?}", reg_type);
-
-		let sock_type = match reg_type {
-			RegistrationType::Lobby => Ok(SocketType::Socket),
-			RegistrationType::HostClient => match req.sock_type {
-				Some(ref st) => match st.as_str() {
-					"host" => Ok(SocketType::Host),
-					_ => Ok(SocketType::Client),
-				},
-				None => Err(reject::custom(Rejections::InvalidSockType)),
-			},
-		}?;
-
-		log!(
-			out,
-			Color::Blue,
-			"Got sock_type {:?}, upgrading...",
-			sock_type
-		);
+		};
+
+		let options = ConnectOptions {
+			envelope: req.envelope.unwrap_or(false),
+			control: req.control.unwrap_or(false),
+			privileged: has_host_key,
+		};
+
+		if options.control && !has_host_key {
+			warn!("Rejecting because only connections with the host key can send commands");
+			return Err(metrics::reject(Rejections::HostKeyRequired));
+		}
+
+		if let Some(reg) = regists.get(&req.id) {
+			// the keys may have been rotated while they were being verified
+			if !Arc::ptr_eq(&reg.key, &key)
+				|| (has_host_key && !Arc::ptr_eq(&reg.host_key, &host_key))
+			{
+				warn!("Rejecting because the keys changed while they were being verified");
+				return Err(metrics::reject(Rejections::IncorrectKey));
+			}
+
+			let sends_for_everyone = matches!(sock_type, SocketType::Host | SocketType::Publisher);
+
+			if sends_for_everyone && reg.host_auth && !has_host_key {
+				warn!(
+					"Rejecting because joining as a {:?} requires the host key",
+					sock_type
+				);
+				return Err(metrics::reject(Rejections::HostKeyRequired));
+			}
+		}
+
+		if let Some(ref token) = req.resume {
+			let resumable = match regists.get(&req.id) {
+				Some(reg) => reg.can_resume(token).await,
+				None => false,
+			};
+
+			if !resumable {
+				warn!("Rejecting because the resume token is invalid or expired");
+				return Err(metrics::reject(Rejections::InvalidResumeToken));
+			}
+		} else if let Some(reg) = regists.get(&req.id) {
+			// a connection that's resuming already has its place, so it can't be turned away
+			if *reg.locked.read().await && !has_host_key {
+				warn!("Rejecting because the registration is locked");
+				return Err(metrics::reject(Rejections::RegistrationLocked));
+			}
+
+			if reg.is_full(sock_type).await {
+				warn!("Rejecting because the registration is full");
+				return Err(metrics::reject(Rejections::RegistrationFull));
+			}
+		}
+
+		// tungstenite refuses anything over these limits before it's read into memory
+		let limits = match regists.get(&req.id) {
+			Some(reg) => reg.message_limits.settings(&*CONFIG.read().await),
+			None => return Err(metrics::not_found()),
+		};
+
+		info!("Got sock_type {:?}, upgrading...", sock_type);
+
+		let resume = req.resume.clone();
+
+		let ws = ws
+			.max_message_size(limits.max_message_size)
+			.max_frame_size(limits.max_frame_size);
 
 		Ok(ws.on_upgrade(move |socket| {
-			Socket::spawn_forwarding(socket, req.id.to_owned(), registrations, sock_type)
+			Socket::spawn_forwarding(
+				socket,
+				req.id.to_owned(),
+				registrations,
+				sock_type,
+				options,
+				resume,
+				addr,
+			)
 		}))
 	}
 
+	#[tracing::instrument(
+		name = "connection",
+		skip_all,
+		fields(reg_id = %id, conn_id = tracing::field::Empty)
+	)]
 	pub async fn spawn_forwarding(
 		ws: WebSocket,
 		id: String,
 		registrations: Registrations,
 		sock_type: SocketType,
+		options: ConnectOptions,
+		resume: Option<String>,
+		addr: Option<SocketAddr>,
 	) {
-		let (out, vbs) = Config::out_and_vbs().await;
-
-		log_vbs!(
-			vbs,
-			out,
-			"Spawning forwarding for socket with id {} and sock_type {:?}",
-			id,
+		debug!(
+			"Spawning forwarding for socket with sock_type {:?}",
 			sock_type
 		);
 
-		let (ws_sender, ws_receiver) = ws.split();
+		let (mut ws_sender, ws_receiver) = ws.split();
 
 		let reg_clone = registrations.clone();
 		let mut registers = registrations.write().await;
 
 		if let Some(reg) = registers.get_mut(&id) {
-			let uuid = reg.add_connection(ws_sender, sock_type).await;
-			reg.spawn_sending(ws_receiver, sock_type, reg_clone, uuid, id);
+			// the token could have expired while upgrading, in which case this just becomes a
+			// brand new connection
+			let resumed = match resume {
+				Some(token) => reg.take_detached(&token).await,
+				None => None,
+			};
+
+			let sock_type = resumed.as_ref().map_or(sock_type, |d| d.sock_type);
+
+			// another connection could have taken the last place while this one was upgrading
+			if resumed.is_none() && reg.is_full(sock_type).await {
+				drop(registers);
+				warn!("Closing because the registration filled up while upgrading");
+
+				let close = Message::close_with(close_codes::FULL, "Registration is full");
+				if let Err(err) = ws_sender.send(close).await {
+					debug!("Failed to send close frame: {}", err);
+				}
+				return;
+			}
+
+			let uuid = reg
+				.add_connection(ws_sender, sock_type, resumed, options.privileged, addr)
+				.await;
+			tracing::Span::current().record("conn_id", tracing::field::display(&uuid));
+
+			reg.spawn_sending(ws_receiver, sock_type, reg_clone, uuid, id, options);
 		}
 
-		log_vbs!(
-			vbs,
-			out,
-			"Successfully added connection and spawned forwarding"
-		);
+		debug!("Successfully added connection and spawned forwarding");
 	}
 }
 
-#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+#[derive(Eq, PartialEq, Copy, Clone, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum SocketType {
 	Socket,
 	Host,
 	Client,
+	Publisher,
+	Subscriber,
+}
+
+impl SocketType {
+	/// The kind of connection that a request with `sock_type` joins a registration of
+	/// `reg_type` as, or `None` if it can't join it. Lobbies only have one kind, so they don't
+	/// need a `sock_type`.
+	pub fn joining(reg_type: RegistrationType, sock_type: Option<&str>) -> Option<SocketType> {
+		// remove potential trailing slashes 'cause that's what the rust URL crate adds
+		let sock_type = sock_type.map(|st| st.replace('/', ""));
+
+		match (reg_type, sock_type.as_deref()) {
+			(RegistrationType::Lobby, _) => Some(SocketType::Socket),
+			(RegistrationType::HostClient, Some("host")) => Some(SocketType::Host),
+			(RegistrationType::HostClient, Some("client")) => Some(SocketType::Client),
+			(RegistrationType::Broadcast, Some("publisher")) => Some(SocketType::Publisher),
+			(RegistrationType::Broadcast, Some("subscriber")) => Some(SocketType::Subscriber),
+			_ => None,
+		}
+	}
+
+	/// The kind of connection that messages from this kind of connection are sent to, or
+	/// `None` if it isn't allowed to send messages at all
+	pub fn recipients(&self) -> Option<SocketType> {
+		match self {
+			SocketType::Socket => Some(SocketType::Socket),
+			SocketType::Client => Some(SocketType::Host),
+			SocketType::Host => Some(SocketType::Client),
+			SocketType::Publisher => Some(SocketType::Subscriber),
+			SocketType::Subscriber => None,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn joining_ignores_trailing_slashes() {
+		for (reg_type, sock_type, expected) in [
+			(RegistrationType::Lobby, None, Some(SocketType::Socket)),
+			(
+				RegistrationType::Lobby,
+				Some("host"),
+				Some(SocketType::Socket),
+			),
+			(
+				RegistrationType::HostClient,
+				Some("host/"),
+				Some(SocketType::Host),
+			),
+			(
+				RegistrationType::HostClient,
+				Some("client"),
+				Some(SocketType::Client),
+			),
+			(RegistrationType::HostClient, Some("publisher"), None),
+			(RegistrationType::HostClient, None, None),
+			(
+				RegistrationType::Broadcast,
+				Some("publisher/"),
+				Some(SocketType::Publisher),
+			),
+			(
+				RegistrationType::Broadcast,
+				Some("subscriber"),
+				Some(SocketType::Subscriber),
+			),
+			(RegistrationType::Broadcast, Some("host"), None),
+			(RegistrationType::Broadcast, None, None),
+		] {
+			assert_eq!(SocketType::joining(reg_type, sock_type), expected);
+		}
+	}
+
+	#[test]
+	fn subscribers_cant_send() {
+		assert_eq!(
+			SocketType::Publisher.recipients(),
+			Some(SocketType::Subscriber)
+		);
+		assert_eq!(SocketType::Host.recipients(), Some(SocketType::Client));
+		assert_eq!(SocketType::Client.recipients(), Some(SocketType::Host));
+		assert_eq!(SocketType::Socket.recipients(), Some(SocketType::Socket));
+		assert_eq!(SocketType::Subscriber.recipients(), None);
+	}
 }
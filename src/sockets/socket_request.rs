@@ -1,8 +1,24 @@
+use crate::secret::Secret;
 use serde::Deserialize;
 
 #[derive(Deserialize, Debug)]
 pub struct SocketRequest {
-	pub key: String,
+	pub key: Secret,
 	pub id: String,
 	pub sock_type: Option<String>,
+	pub envelope: Option<bool>,
+	pub resume: Option<String>,
+	pub host_key: Option<Secret>,
+	pub control: Option<bool>,
+}
+
+/// What a connection asked the router to do with its messages when it connected
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ConnectOptions {
+	/// Messages may be addressed to a single other connection
+	pub envelope: bool,
+	/// Messages may be commands for the router, if this connection has the host key
+	pub control: bool,
+	/// This connection sent the registration's host key
+	pub privileged: bool,
 }
@@ -1,14 +1,171 @@
 use crate::{
-	config::{Color, Config},
-	log, Registrations,
+	admin, limits, metrics,
+	register::{ConnectionCounts, RateLimitPolicy, RegistrationType},
+	Registrations, CONFIG,
+};
+use chrono::Utc;
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::{
+	convert::TryInto,
+	net::SocketAddr,
+	str::FromStr,
+	sync::{atomic::Ordering, Mutex},
+	time::Duration,
 };
-use std::convert::TryInto;
 use sysinfo::{ProcessExt, SystemExt};
+use tracing::{debug, info, info_span, Instrument};
 use warp::{Rejection, Reply};
 
-pub async fn return_stats(rgs: Registrations) -> Result<impl Reply, Rejection> {
-	log!(true, Color::Yellow, "Requesting stats on server...");
+lazy_static! {
+	// filled in by the sampler, so that requests never have to wait on sysinfo
+	static ref SYSTEM: Mutex<Option<SystemStats>> = Mutex::new(None);
+}
+
+/// Who may see `/stats`, and how much of it they see
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub enum StatsAccess {
+	/// Only requests with the admin token can see stats, and they see everything
+	Admin,
+	/// Anyone can see counts of registrations and connections, but not their ids or anything
+	/// about the host. Requests with the admin token still see everything.
+	Aggregate,
+	/// `/stats` doesn't exist
+	Disabled,
+}
+
+impl FromStr for StatsAccess {
+	type Err = ();
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"admin" => Ok(StatsAccess::Admin),
+			"aggregate" => Ok(StatsAccess::Aggregate),
+			"disabled" => Ok(StatsAccess::Disabled),
+			_ => Err(()),
+		}
+	}
+}
+
+#[derive(Serialize, Clone, Copy)]
+struct SystemStats {
+	total_mem: u64,
+	used_mem: u64,
+	proc_mem: i64,
+	/// when these were sampled, as a unix timestamp
+	sampled_at: i64,
+}
+
+/// Spawns a task that samples the host's memory usage every `stats_interval` seconds, which is
+/// much cheaper than asking sysinfo about the whole system every time someone requests stats.
+/// Nothing is sampled if nobody can see it.
+pub fn spawn_sampler() {
+	tokio::spawn(
+		async move {
+			let conf = CONFIG.read().await;
+			let (access, stats_interval) = (conf.stats, conf.stats_interval);
+			drop(conf);
+
+			if access == StatsAccess::Disabled {
+				return;
+			}
+
+			let mut system = sysinfo::System::new();
+			let pid = std::process::id().try_into().ok();
+			let mut interval = tokio::time::interval(Duration::from_secs(stats_interval));
+
+			loop {
+				interval.tick().await;
+				debug!("Sampling system stats...");
+
+				system.refresh_memory();
+
+				let proc_mem = pid
+					.and_then(|pid| {
+						system.refresh_process(pid);
+						system.process(pid).map(|proc| proc.memory() as i64)
+					})
+					.unwrap_or(-1);
+
+				let sample = SystemStats {
+					total_mem: system.total_memory(),
+					used_mem: system.used_memory(),
+					proc_mem,
+					sampled_at: Utc::now().timestamp(),
+				};
+
+				*SYSTEM.lock().unwrap_or_else(|p| p.into_inner()) = Some(sample);
+			}
+		}
+		.instrument(info_span!("stats_sampler")),
+	);
+}
+
+pub async fn return_stats(
+	auth: Option<String>,
+	addr: Option<SocketAddr>,
+	rgs: Registrations,
+) -> Result<impl Reply, Rejection> {
+	info!("Requesting stats on server...");
+
+	let conf = CONFIG.read().await;
+	let (access, token) = (conf.stats, conf.admin_token.clone());
+	drop(conf);
+
+	if access == StatsAccess::Disabled {
+		return Err(metrics::not_found());
+	}
+
+	// only requests that try to authenticate are rate limited, since they're the only ones that
+	// could be guessing the token
+	if auth.is_some() {
+		limits::check_ip(addr).await?;
+	}
+
+	let is_admin = token.is_some_and(|t| admin::token_matches(&t, auth.as_deref()));
+
+	if is_admin {
+		Ok(full_stats(&rgs).await.to_string())
+	} else if access == StatsAccess::Aggregate {
+		Ok(aggregate_stats(&rgs).await.to_string())
+	} else {
+		Err(metrics::reject(admin::Rejections::InvalidToken))
+	}
+}
 
+/// Totals across every registration, without anything that identifies them
+async fn aggregate_stats(rgs: &Registrations) -> serde_json::Value {
+	let regs = rgs.read().await;
+
+	let mut counts = ConnectionCounts::default();
+	let (mut hostclient, mut lobby, mut broadcast) = (0, 0, 0);
+
+	for r in regs.values() {
+		for con in r.connections.read().await.iter() {
+			counts.add(con.sock_type);
+		}
+
+		match r.reg_type {
+			RegistrationType::HostClient => hostclient += 1,
+			RegistrationType::Lobby => lobby += 1,
+			RegistrationType::Broadcast => broadcast += 1,
+		}
+	}
+
+	serde_json::json!({
+		"registrations": regs.len(),
+		"reg_types": {
+			"hostclient": hostclient,
+			"lobby": lobby,
+			"broadcast": broadcast
+		},
+		"counts": counts,
+		"queues": queue_info(),
+		"rate_limited": rate_limit_info()
+	})
+}
+
+async fn full_stats(rgs: &Registrations) -> serde_json::Value {
 	let regs = rgs.read().await;
 
 	let mut reg_info = Vec::new();
@@ -16,42 +173,72 @@ pub async fn return_stats(rgs: Registrations) -> Result<impl Reply, Rejection> {
 	for (k, r) in regs.iter() {
 		let conns = r.connections.read().await;
 		let con_len = conns.len();
+
+		let (mut queued, mut sent, mut dropped, mut rate_limited) = (0, 0, 0, 0);
+		let mut rtt_ms = serde_json::Map::new();
+		for con in conns.iter() {
+			queued += con.queue.len();
+			sent += con.queue.sent.load(Ordering::Relaxed);
+			dropped += con.queue.dropped.load(Ordering::Relaxed);
+			rate_limited += con.rate_limited.load(Ordering::Relaxed);
+			rtt_ms.insert(con.uuid.to_owned(), con.rtt_ms().into());
+		}
 		drop(conns);
 
 		let destroy = *(r.destroy.read().await);
+		let locked = *(r.locked.read().await);
+		let counts = r.connection_counts().await;
 
 		reg_info.push(serde_json::json!({
 			"id": k,
 			"connections": con_len,
 			"reg_type": format!("{:?}", r.reg_type),
-			"destroy": destroy
+			"destroy": destroy,
+			"locked": locked,
+			"queued": queued,
+			"sent": sent,
+			"dropped": dropped,
+			"rate_limited": rate_limited,
+			"counts": counts,
+			"limits": r.limits,
+			"keepalive": r.keepalive,
+			"rtt_ms": rtt_ms
 		}));
 	}
 
-	let mut system = sysinfo::System::new_all();
-	system.refresh_memory();
+	// `null` until the sampler has run for the first time
+	let sys_info = *SYSTEM.lock().unwrap_or_else(|p| p.into_inner());
 
-	let proc_usage = std::process::id()
-		.try_into()
-		.ok()
-		.and_then(|pid| {
-			system.refresh_process(pid);
+	serde_json::json!({
+		"registrations": reg_info,
+		"queues": queue_info(),
+		"rate_limited": rate_limit_info(),
+		"system": sys_info
+	})
+}
 
-			system.process(pid)
-				.map(|proc| proc.memory() as i64)
-		})
-		.unwrap_or(-1);
+/// How many messages were over their connection's rate limits, by what happened to them
+fn rate_limit_info() -> serde_json::Value {
+	let mut info = serde_json::Map::new();
 
-	let sys_info = serde_json::json!({
-		"total_mem": system.total_memory(),
-		"used_mem": system.used_memory(),
-		"proc_mem": proc_usage
-	});
+	for policy in [
+		RateLimitPolicy::Drop,
+		RateLimitPolicy::Delay,
+		RateLimitPolicy::Disconnect,
+	] {
+		let count = metrics::RATE_LIMITED_MESSAGES
+			.with_label_values(&[policy.as_str()])
+			.get();
 
-	let ret = serde_json::json!({
-		"registrations": reg_info,
-		"system": sys_info
-	});
+		info.insert(policy.as_str().to_owned(), count.into());
+	}
+
+	info.into()
+}
 
-	Ok(ret.to_string())
+fn queue_info() -> serde_json::Value {
+	serde_json::json!({
+		"dropped": metrics::DROPPED_MESSAGES.get(),
+		"slow_disconnects": metrics::SLOW_DISCONNECTS.get()
+	})
 }
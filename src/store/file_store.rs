@@ -0,0 +1,138 @@
+use crate::store::*;
+use std::{
+	collections::HashMap,
+	fs::{self, File},
+	io::{ErrorKind, Write},
+	path::{Path, PathBuf},
+	sync::Mutex,
+};
+
+/// Keeps every record in a single JSON file on disk. The whole file is rewritten on every
+/// change, which is fine for the number of registrations a single router handles.
+pub struct FileStore {
+	path: PathBuf,
+	records: Mutex<HashMap<String, RegistrationRecord>>,
+}
+
+impl FileStore {
+	pub fn open<P: AsRef<Path>>(path: P) -> Result<FileStore, StoreError> {
+		let path = path.as_ref().to_path_buf();
+
+		let records = match fs::read(&path) {
+			Ok(bytes) if bytes.is_empty() => HashMap::new(),
+			Ok(bytes) => serde_json::from_slice::<Vec<RegistrationRecord>>(&bytes)?
+				.into_iter()
+				.map(|r| (r.uuid.to_owned(), r))
+				.collect(),
+			Err(err) if err.kind() == ErrorKind::NotFound => HashMap::new(),
+			Err(err) => return Err(err.into()),
+		};
+
+		Ok(FileStore {
+			path,
+			records: Mutex::new(records),
+		})
+	}
+
+	/// Writes `records` to a new store at `path`, replacing anything that was already there
+	pub fn write_snapshot<P: AsRef<Path>>(
+		path: P,
+		records: Vec<RegistrationRecord>,
+	) -> Result<(), StoreError> {
+		let store = FileStore {
+			path: path.as_ref().to_path_buf(),
+			records: Mutex::new(HashMap::new()),
+		};
+
+		let records = records
+			.into_iter()
+			.map(|r| (r.uuid.to_owned(), r))
+			.collect();
+
+		store.flush(&records)
+	}
+
+	fn flush(&self, records: &HashMap<String, RegistrationRecord>) -> Result<(), StoreError> {
+		let list: Vec<&RegistrationRecord> = records.values().collect();
+		let bytes = serde_json::to_vec(&list)?;
+
+		// write to a temporary file first and then rename it so that we never leave a
+		// half-written store behind if we crash in the middle of writing. It has to be synced
+		// before the rename, or the rename could reach the disk before the contents do.
+		let mut tmp_path = self.path.clone().into_os_string();
+		tmp_path.push(".tmp");
+
+		let mut tmp = File::create(&tmp_path)?;
+		tmp.write_all(&bytes)?;
+		tmp.sync_all()?;
+		drop(tmp);
+
+		fs::rename(&tmp_path, &self.path)?;
+
+		Ok(())
+	}
+}
+
+impl RegistrationStore for FileStore {
+	fn load(&self) -> Result<Vec<RegistrationRecord>, StoreError> {
+		let records = self.records.lock().map_err(|_| StoreError::Poisoned)?;
+		Ok(records.values().cloned().collect())
+	}
+
+	fn insert(&self, record: RegistrationRecord) -> Result<(), StoreError> {
+		let mut records = self.records.lock().map_err(|_| StoreError::Poisoned)?;
+		records.insert(record.uuid.to_owned(), record);
+		self.flush(&records)
+	}
+
+	fn remove(&self, uuid: &str) -> Result<(), StoreError> {
+		let mut records = self.records.lock().map_err(|_| StoreError::Poisoned)?;
+
+		if records.remove(uuid).is_some() {
+			self.flush(&records)
+		} else {
+			Ok(())
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::register::RegistrationType;
+
+	fn record(uuid: &str) -> RegistrationRecord {
+		RegistrationRecord::minimal(uuid, RegistrationType::Lobby)
+	}
+
+	fn temp_path() -> PathBuf {
+		std::env::temp_dir().join(format!("ws_router_store_{}.json", uuid::Uuid::new_v4()))
+	}
+
+	#[test]
+	fn reopens_with_the_same_records() {
+		let path = temp_path();
+
+		let store = FileStore::open(&path).unwrap();
+		store.insert(record("a")).unwrap();
+		store.insert(record("b")).unwrap();
+		store.remove("a").unwrap();
+
+		let mut tmp_path = path.clone().into_os_string();
+		tmp_path.push(".tmp");
+		assert!(!Path::new(&tmp_path).exists());
+
+		let reopened = FileStore::open(&path).unwrap().load().unwrap();
+		let _ = fs::remove_file(&path);
+
+		assert_eq!(reopened.len(), 1);
+		assert_eq!(reopened[0].uuid, "b");
+		assert_eq!(reopened[0].reg_type, RegistrationType::Lobby);
+	}
+
+	#[test]
+	fn a_missing_file_is_an_empty_store() {
+		let store = FileStore::open(temp_path()).unwrap();
+		assert!(store.load().unwrap().is_empty());
+	}
+}
@@ -0,0 +1,28 @@
+use crate::store::*;
+use std::{collections::HashMap, sync::Mutex};
+
+/// Keeps records only for as long as the process is running. This is the default, and matches
+/// how the server behaved before stores existed.
+#[derive(Default)]
+pub struct MemoryStore {
+	records: Mutex<HashMap<String, RegistrationRecord>>,
+}
+
+impl RegistrationStore for MemoryStore {
+	fn load(&self) -> Result<Vec<RegistrationRecord>, StoreError> {
+		let records = self.records.lock().map_err(|_| StoreError::Poisoned)?;
+		Ok(records.values().cloned().collect())
+	}
+
+	fn insert(&self, record: RegistrationRecord) -> Result<(), StoreError> {
+		let mut records = self.records.lock().map_err(|_| StoreError::Poisoned)?;
+		records.insert(record.uuid.to_owned(), record);
+		Ok(())
+	}
+
+	fn remove(&self, uuid: &str) -> Result<(), StoreError> {
+		let mut records = self.records.lock().map_err(|_| StoreError::Poisoned)?;
+		records.remove(uuid);
+		Ok(())
+	}
+}
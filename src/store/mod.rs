@@ -0,0 +1,45 @@
+pub use file_store::*;
+pub use memory_store::*;
+pub use registration_record::*;
+pub use store_error::*;
+pub use writer::*;
+
+mod file_store;
+mod memory_store;
+mod registration_record;
+mod store_error;
+mod writer;
+
+use std::str::FromStr;
+
+/// A place to keep the durable parts of every registration, so that they can be reloaded
+/// when the server restarts. Connections are never stored, since they can't survive a restart
+/// anyways.
+pub trait RegistrationStore: Send + Sync {
+	/// Returns every registration that is currently saved in the store
+	fn load(&self) -> Result<Vec<RegistrationRecord>, StoreError>;
+
+	/// Saves the record, overwriting any record that already exists with the same uuid
+	fn insert(&self, record: RegistrationRecord) -> Result<(), StoreError>;
+
+	/// Removes the record with the given uuid, if it exists
+	fn remove(&self, uuid: &str) -> Result<(), StoreError>;
+}
+
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub enum StoreType {
+	Memory,
+	File,
+}
+
+impl FromStr for StoreType {
+	type Err = ();
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"memory" => Ok(StoreType::Memory),
+			"file" => Ok(StoreType::File),
+			_ => Err(()),
+		}
+	}
+}
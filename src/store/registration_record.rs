@@ -0,0 +1,58 @@
+use crate::register::{
+	ConnectionLimits, Keepalive, MessageLimits, Metadata, RateLimits, RegistrationType,
+};
+use serde::{Deserialize, Serialize};
+
+/// Everything about a registration that needs to survive a restart. `key` and `host_key` are
+/// the argon2 hashes, never the keys that the client sent. New fields must have a
+/// `#[serde(default)]` so that stores written by older versions can still be loaded.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RegistrationRecord {
+	pub uuid: String,
+	pub key: String,
+	pub host_key: String,
+	pub reg_type: RegistrationType,
+	#[serde(default)]
+	pub expires_at: Option<i64>,
+	#[serde(default)]
+	pub presence: bool,
+	#[serde(default)]
+	pub resume: bool,
+	#[serde(default)]
+	pub limits: ConnectionLimits,
+	#[serde(default)]
+	pub metadata: Metadata,
+	#[serde(default)]
+	pub public: bool,
+	#[serde(default)]
+	pub host_auth: bool,
+	#[serde(default)]
+	pub keepalive: Keepalive,
+	#[serde(default)]
+	pub message_limits: MessageLimits,
+	#[serde(default)]
+	pub rate_limits: RateLimits,
+	#[serde(default = "used_by_default")]
+	pub used: bool,
+}
+
+// stores written before this was saved can't say whether anyone connected, so their
+// registrations are kept rather than being reaped as unused
+fn used_by_default() -> bool {
+	true
+}
+
+#[cfg(test)]
+impl RegistrationRecord {
+	/// A record with only the fields that are required, and every other one left to its
+	/// default like it would be in a store written by an older version
+	pub fn minimal(uuid: &str, reg_type: RegistrationType) -> RegistrationRecord {
+		serde_json::from_value(serde_json::json!({
+			"uuid": uuid,
+			"key": "key",
+			"host_key": "host_key",
+			"reg_type": reg_type
+		}))
+		.unwrap()
+	}
+}
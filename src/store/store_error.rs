@@ -0,0 +1,11 @@
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum StoreError {
+	#[error("Failed to read or write the store file: {0}")]
+	Io(#[from] std::io::Error),
+	#[error("Failed to (de)serialize the store file: {0}")]
+	Serde(#[from] serde_json::Error),
+	#[error("The store's lock was poisoned")]
+	Poisoned,
+}
@@ -0,0 +1,128 @@
+use crate::store::*;
+use std::{sync::mpsc, thread};
+use tokio::sync::oneshot;
+use tracing::{debug, error};
+
+enum Op {
+	Insert(Box<RegistrationRecord>),
+	Remove(String),
+	Sync(oneshot::Sender<()>),
+}
+
+/// Makes changes to a store on a thread of its own. Stores may do blocking I/O, so this keeps it
+/// off of the runtime, and means that nobody has to wait for it while holding the registrations
+/// lock. Changes are made in the order that they were sent, so sending them while holding that
+/// lock keeps the store in the same order as the registrations map.
+pub struct StoreWriter {
+	tx: mpsc::Sender<Op>,
+}
+
+impl StoreWriter {
+	/// Starts the thread that writes to `store`. It stops once this is dropped and everything
+	/// that was already sent has been written.
+	pub fn spawn(store: Box<dyn RegistrationStore>) -> StoreWriter {
+		let (tx, rx) = mpsc::channel();
+
+		thread::Builder::new()
+			.name("store_writer".to_owned())
+			.spawn(move || {
+				for op in rx {
+					match op {
+						Op::Insert(record) => {
+							let uuid = record.uuid.to_owned();
+
+							match store.insert(*record) {
+								Ok(_) => debug!(reg_id = %uuid, "Persisted registration"),
+								Err(err) => {
+									error!(reg_id = %uuid, "Failed to persist registration: {}", err)
+								}
+							}
+						}
+						Op::Remove(uuid) => match store.remove(&uuid) {
+							Ok(_) => debug!(reg_id = %uuid, "Removed registration from store"),
+							Err(err) => error!(
+								reg_id = %uuid,
+								"Failed to remove registration from store: {}", err
+							),
+						},
+						Op::Sync(done) => {
+							let _ = done.send(());
+						}
+					}
+				}
+			})
+			.expect("Failed to spawn the store writer thread");
+
+		StoreWriter { tx }
+	}
+
+	pub fn insert(&self, record: RegistrationRecord) {
+		self.send(Op::Insert(Box::new(record)));
+	}
+
+	pub fn remove(&self, uuid: &str) {
+		self.send(Op::Remove(uuid.to_owned()));
+	}
+
+	/// Resolves once everything that was sent before this has been written
+	pub async fn sync(&self) {
+		let (done_tx, done_rx) = oneshot::channel();
+		self.send(Op::Sync(done_tx));
+		let _ = done_rx.await;
+	}
+
+	fn send(&self, op: Op) {
+		// this only fails if the thread panicked, and there's nothing left to write with then
+		if self.tx.send(op).is_err() {
+			error!("The store writer has stopped; changes will not be persisted");
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::register::RegistrationType;
+	use std::{
+		sync::{Arc, Mutex},
+		time::Duration,
+	};
+
+	// tells the test whenever something has been removed, since that's the last change it makes
+	struct Shared(Arc<MemoryStore>, Mutex<mpsc::Sender<()>>);
+
+	impl RegistrationStore for Shared {
+		fn load(&self) -> Result<Vec<RegistrationRecord>, StoreError> {
+			self.0.load()
+		}
+
+		fn insert(&self, record: RegistrationRecord) -> Result<(), StoreError> {
+			self.0.insert(record)
+		}
+
+		fn remove(&self, uuid: &str) -> Result<(), StoreError> {
+			let removed = self.0.remove(uuid);
+			let _ = self.1.lock().unwrap().send(());
+			removed
+		}
+	}
+
+	fn record(uuid: &str) -> RegistrationRecord {
+		RegistrationRecord::minimal(uuid, RegistrationType::HostClient)
+	}
+
+	#[test]
+	fn applies_changes_in_order() {
+		let store = Arc::new(MemoryStore::default());
+		let (tx, rx) = mpsc::channel();
+		let writer = StoreWriter::spawn(Box::new(Shared(store.clone(), Mutex::new(tx))));
+
+		writer.insert(record("a"));
+		writer.insert(record("b"));
+		writer.remove("a");
+		rx.recv_timeout(Duration::from_secs(5)).unwrap();
+
+		let uuids: Vec<String> = store.load().unwrap().into_iter().map(|r| r.uuid).collect();
+		assert_eq!(uuids, vec!["b".to_owned()]);
+	}
+}